@@ -0,0 +1,98 @@
+// Splits a request or reply line into words. A word is either a run of
+// non-blank characters, taken literally, or a double-quoted string in which
+// \" \\ \n \r and \t are the only escapes, so any text can be passed as an
+// argument. A quoted string must be followed by a blank or the end of the line.
+pub fn split(line: &str) -> Result<Vec<String>, &'static str> {
+	let mut words = Vec::new();
+	let mut chars = line.chars().peekable();
+	loop {
+		while chars.peek().is_some_and(|c| c.is_whitespace()) {
+			chars.next();
+		}
+		let mut word = String::new();
+		match chars.next() {
+			None => return Ok(words),
+			Some('"') => {
+				loop {
+					match chars.next() {
+						None => return Err("Unterminated quoted string."),
+						Some('"') => break,
+						Some('\\') => word.push(match chars.next() {
+							Some('"') => '"',
+							Some('\\') => '\\',
+							Some('n') => '\n',
+							Some('r') => '\r',
+							Some('t') => '\t',
+							_ => return Err("Invalid escape sequence."),
+						}),
+						Some(c) => word.push(c),
+					}
+				}
+				if chars.peek().is_some_and(|c| !c.is_whitespace()) {
+					return Err("Quoted string not followed by a blank.");
+				}
+			},
+			Some(c) => {
+				word.push(c);
+				while let Some(&c) = chars.peek() {
+					if c.is_whitespace() {
+						break;
+					}
+					word.push(c);
+					chars.next();
+				}
+			},
+		}
+		words.push(word);
+	}
+}
+
+// Quotes a word unless split would read it back unchanged as a bare word.
+pub fn quote(word: &str) -> String {
+	if !word.is_empty() && !word.starts_with('"') && !word.contains(char::is_whitespace) {
+		return word.to_string();
+	}
+	let mut quoted = String::from("\"");
+	for c in word.chars() {
+		match c {
+			'"' => quoted.push_str("\\\""),
+			'\\' => quoted.push_str("\\\\"),
+			'\n' => quoted.push_str("\\n"),
+			'\r' => quoted.push_str("\\r"),
+			'\t' => quoted.push_str("\\t"),
+			_ => quoted.push(c),
+		}
+	}
+	quoted.push('"');
+	quoted
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn bare_and_quoted_words() {
+		assert_eq!(split("LOGIN bob pa\"ss\\\r\n").unwrap(), vec!["LOGIN", "bob", "pa\"ss\\"]);
+		assert_eq!(split("LOGIN \"bob\" \"correct horse\\tbattery \\\"staple\\\" \\\\\"\r\n").unwrap(),
+			vec!["LOGIN", "bob", "correct horse\tbattery \"staple\" \\"]);
+		assert_eq!(split("CREATE \"\" \"p\u{e4}ss w\u{f6}rd\"").unwrap(), vec!["CREATE", "", "p\u{e4}ss w\u{f6}rd"]);
+		assert_eq!(split("  \r\n").unwrap(), Vec::<String>::new());
+	}
+
+	#[test]
+	fn quoted_words_split_back() {
+		let words = ["alice", "", "\"quoted\"", "Mozilla/5.0 (X11; Linux)", "tab\there\r\n\\"];
+		let line: Vec<String> = words.iter().map(|w| quote(w)).collect();
+		assert_eq!(split(line.join(" ").as_str()).unwrap(), words);
+		assert_eq!(quote("alice"), "alice");
+	}
+
+	#[test]
+	fn malformed_lines_are_rejected() {
+		assert!(split("LOGIN bob \"secret\r\n").is_err());
+		assert!(split("LOGIN bob \"sec\\qret\"\r\n").is_err());
+		assert!(split("LOGIN bob \"sec\"ret\r\n").is_err());
+		assert!(split("LOGIN bob \"secret\\").is_err());
+	}
+}
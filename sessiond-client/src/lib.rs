@@ -0,0 +1,376 @@
+use std::error;
+use std::fmt;
+use std::io::prelude::*;
+use std::io::{self, BufReader};
+use std::ops::Deref;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// The quoting both ends of the line protocol share.
+pub mod args;
+
+use args::{quote, split};
+
+#[derive(Debug)]
+pub enum Error {
+	// The daemon rejected the request.
+	AuthenticationFailed,
+	LoginFailed,
+	// The account is locked after too many failed attempts. Only reported by
+	// daemons that speak protocol version 2; older ones say LoginFailed or
+	// AuthenticationFailed.
+	Locked,
+	SessionNotFound,
+	UserNotFound,
+	UserExists,
+	// The name does not meet the daemon's username policy.
+	InvalidName,
+	// The user already holds as many sessions as the daemon allows.
+	SessionLimit,
+	AttributeNotFound,
+	// The attribute key is empty, or the key, the value or the session's
+	// attributes as a whole exceed the daemon's size limits.
+	AttributeLimit,
+	// The connecting user is not allowed to run the command.
+	Denied,
+	// The daemon does not know the command.
+	UnknownCommand,
+	// Any other NG reply, with the daemon's message.
+	Server(String),
+	// An argument a version 1 daemon cannot carry: empty or with whitespace.
+	InvalidArgument,
+	// A reply that does not follow the protocol.
+	Protocol(String),
+	Io(io::Error),
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Error::AuthenticationFailed => write!(f, "authentication failed"),
+			Error::LoginFailed => write!(f, "login failed"),
+			Error::Locked => write!(f, "account locked"),
+			Error::SessionNotFound => write!(f, "session not found"),
+			Error::UserNotFound => write!(f, "user not found"),
+			Error::UserExists => write!(f, "user already exists"),
+			Error::InvalidName => write!(f, "invalid user name"),
+			Error::SessionLimit => write!(f, "session limit reached"),
+			Error::AttributeNotFound => write!(f, "attribute not found"),
+			Error::AttributeLimit => write!(f, "attribute rejected by size limits"),
+			Error::Denied => write!(f, "permission denied"),
+			Error::UnknownCommand => write!(f, "unknown command"),
+			Error::Server(ref msg) => write!(f, "server error: {}", msg),
+			Error::InvalidArgument => write!(f, "invalid argument"),
+			Error::Protocol(ref reply) => write!(f, "unexpected reply \"{}\"", reply),
+			Error::Io(ref e) => write!(f, "{}", e),
+		}
+	}
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+	fn from(e: io::Error) -> Error {
+		Error::Io(e)
+	}
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SessionId(String);
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Username(String);
+
+impl Deref for SessionId {
+	type Target = str;
+	fn deref(&self) -> &str {
+		&self.0
+	}
+}
+
+impl Deref for Username {
+	type Target = str;
+	fn deref(&self) -> &str {
+		&self.0
+	}
+}
+
+// What the daemon knows about a session: who owns it, when it was opened and
+// last used (Unix seconds), how it was opened ("password" or "create"), the
+// peers that opened and last used it, and the label the client gave.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionInfo {
+	pub name: Username,
+	pub created: i64,
+	pub last_accessed: i64,
+	pub method: String,
+	pub source: String,
+	pub last_source: String,
+	pub label: String,
+}
+
+impl fmt::Display for SessionId {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(&self.0)
+	}
+}
+
+impl fmt::Display for Username {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(&self.0)
+	}
+}
+
+// A connection to sessiond over its Unix socket. The connection is kept open
+// between requests; after an I/O error it is dropped and the next request
+// opens a new one. Requests are never retried, since the daemon may already
+// have carried out one whose reply was lost.
+//
+// Each connection starts with HELLO 2 so failures carry error codes; daemons
+// that predate the handshake answer ERROR and are spoken to in version 1.
+pub struct Client {
+	path: PathBuf,
+	timeout: Option<Duration>,
+	conn: Option<BufReader<UnixStream>>,
+	version: u32,
+	label: Option<String>,
+}
+
+fn read_reply(conn: &mut BufReader<UnixStream>) -> Result<String> {
+	let mut reply = String::new();
+	if conn.read_line(&mut reply)? == 0 {
+		return Err(Error::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed")));
+	}
+	Ok(reply.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+// Tells failures apart by the version 2 code and, where one code covers
+// several cases, by the command that failed.
+fn coded_error(cmd: &str, code: &str, msg: &str) -> Error {
+	match (code, cmd) {
+		("BAD_CREDENTIALS", "AUTH") => Error::AuthenticationFailed,
+		("BAD_CREDENTIALS", _) => Error::LoginFailed,
+		("LOCKED", _) => Error::Locked,
+		("NOT_FOUND", "UPDATE") | ("NOT_FOUND", "DELETE") | ("NOT_FOUND", "UNLOCK") => Error::UserNotFound,
+		("NO_ATTRIBUTE", _) => Error::AttributeNotFound,
+		("NOT_FOUND", _) => Error::SessionNotFound,
+		("EXISTS", _) => Error::UserExists,
+		("INVALID", "SET") | ("LIMIT", "SET") => Error::AttributeLimit,
+		("INVALID", "CREATE") => Error::InvalidName,
+		("LIMIT", _) => Error::SessionLimit,
+		("DENIED", _) => Error::Denied,
+		("UNKNOWN_COMMAND", _) => Error::UnknownCommand,
+		_ => Error::Server(msg.to_string()),
+	}
+}
+
+// Version 1 replies carry only the message.
+fn v1_error(msg: &str) -> Error {
+	match msg {
+		"Authentication failed." => Error::AuthenticationFailed,
+		"Login failed." => Error::LoginFailed,
+		"Session not found." => Error::SessionNotFound,
+		"User not found." => Error::UserNotFound,
+		"User already exists." => Error::UserExists,
+		"Invalid user name." => Error::InvalidName,
+		"Session limit reached." => Error::SessionLimit,
+		"Attribute not found." => Error::AttributeNotFound,
+		"Invalid attribute key." | "Attribute value too large." | "Session attributes full." => Error::AttributeLimit,
+		_ => Error::Server(msg.to_string()),
+	}
+}
+
+impl Client {
+	pub fn connect<P: AsRef<Path>>(path: P) -> Result<Client> {
+		Client::connect_timeout(path, None)
+	}
+	// Like connect, with `timeout` applied to the handshake and every later
+	// request as by set_timeout.
+	pub fn connect_timeout<P: AsRef<Path>>(path: P, timeout: Option<Duration>) -> Result<Client> {
+		let mut client = Client {
+			path: path.as_ref().to_path_buf(),
+			timeout,
+			conn: None,
+			version: 1,
+			label: None,
+		};
+		client.connection()?;
+		Ok(client)
+	}
+	// Sets the read and write timeout for every request; None waits forever.
+	pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+		if let Some(ref conn) = self.conn {
+			conn.get_ref().set_read_timeout(timeout)?;
+			conn.get_ref().set_write_timeout(timeout)?;
+		}
+		self.timeout = timeout;
+		Ok(())
+	}
+	// Sets the label, such as a user agent, that LOGIN and CREATE attach to the
+	// sessions they open. Daemons older than SESSIONINFO reject a label.
+	pub fn set_label(&mut self, label: Option<&str>) {
+		self.label = label.map(str::to_string);
+	}
+	fn connection(&mut self) -> Result<&mut BufReader<UnixStream>> {
+		if self.conn.is_none() {
+			let stream = UnixStream::connect(&self.path)?;
+			stream.set_read_timeout(self.timeout)?;
+			stream.set_write_timeout(self.timeout)?;
+			let mut conn = BufReader::new(stream);
+			conn.get_mut().write_all(b"HELLO 2\r\n")?;
+			self.version = if read_reply(&mut conn)? == "OK 2" { 2 } else { 1 };
+			self.conn = Some(conn);
+		}
+		Ok(self.conn.as_mut().unwrap())
+	}
+	fn exchange(&mut self, request: &str) -> Result<String> {
+		let conn = self.connection()?;
+		conn.get_mut().write_all(request.as_bytes())?;
+		read_reply(conn)
+	}
+	// Sends one command and returns the text after "OK". Daemons that speak
+	// version 2 also accept quoted arguments; older ones only bare words.
+	fn request(&mut self, cmd: &str, args: &[&str]) -> Result<String> {
+		self.connection()?;
+		if self.version < 2 && args.iter().any(|arg| quote(arg) != *arg) {
+			return Err(Error::InvalidArgument);
+		}
+		let mut request = String::from(cmd);
+		for arg in args {
+			request.push(' ');
+			request.push_str(quote(arg).as_str());
+		}
+		request.push_str("\r\n");
+		let reply = match self.exchange(request.as_str()) {
+			Ok(reply) => reply,
+			Err(e) => {
+				self.conn = None;
+				return Err(e);
+			},
+		};
+		if reply == "OK" {
+			return Ok(String::new());
+		}
+		if let Some(result) = reply.strip_prefix("OK ") {
+			return Ok(result.to_string());
+		}
+		if let Some(msg) = reply.strip_prefix("NG ") {
+			if self.version >= 2 {
+				let (code, msg) = msg.split_once(' ').unwrap_or((msg, ""));
+				return Err(coded_error(cmd, code, msg));
+			}
+			return Err(v1_error(msg));
+		}
+		Err(match reply.as_str() {
+			"DENIED" => Error::Denied,
+			"ERROR" => Error::UnknownCommand,
+			_ => Error::Protocol(reply),
+		})
+	}
+	fn value(&mut self, cmd: &str, args: &[&str]) -> Result<String> {
+		let result = self.request(cmd, args)?;
+		if result.is_empty() {
+			return Err(Error::Protocol(String::from("OK")));
+		}
+		Ok(result)
+	}
+	pub fn auth(&mut self, name: &str, pass: &str) -> Result<()> {
+		self.request("AUTH", &[name, pass]).map(|_| ())
+	}
+	fn labeled(&mut self, cmd: &str, name: &str, pass: &str) -> Result<SessionId> {
+		let label = self.label.clone();
+		match label {
+			Some(label) => self.value(cmd, &[name, pass, label.as_str()]),
+			None => self.value(cmd, &[name, pass]),
+		}.map(SessionId)
+	}
+	pub fn login(&mut self, name: &str, pass: &str) -> Result<SessionId> {
+		self.labeled("LOGIN", name, pass)
+	}
+	pub fn session(&mut self, session_id: &str) -> Result<Username> {
+		self.value("SESSION", &[session_id]).map(Username)
+	}
+	// Unlike session, does not count as a use of the session.
+	pub fn session_info(&mut self, session_id: &str) -> Result<SessionInfo> {
+		let result = self.value("SESSIONINFO", &[session_id])?;
+		let words = split(result.as_str()).map_err(|_| Error::Protocol(result.clone()))?;
+		let mut info = SessionInfo {
+			name: Username(String::new()),
+			created: 0,
+			last_accessed: 0,
+			method: String::new(),
+			source: String::new(),
+			last_source: String::new(),
+			label: String::new(),
+		};
+		for pair in words.chunks(2) {
+			let (key, val) = match *pair {
+				[ref key, ref val] => (key.as_str(), val.clone()),
+				_ => return Err(Error::Protocol(result)),
+			};
+			match key {
+				"name" => info.name = Username(val),
+				"created" => info.created = val.parse().map_err(|_| Error::Protocol(result.clone()))?,
+				"last_accessed" => info.last_accessed = val.parse().map_err(|_| Error::Protocol(result.clone()))?,
+				"method" => info.method = val,
+				"source" => info.source = val,
+				"last_source" => info.last_source = val,
+				"label" => info.label = val,
+				// Fields added by newer daemons.
+				_ => {},
+			}
+		}
+		Ok(info)
+	}
+	pub fn logout(&mut self, session_id: &str) -> Result<Username> {
+		self.value("LOGOUT", &[session_id]).map(Username)
+	}
+	pub fn create_user(&mut self, name: &str, pass: &str) -> Result<SessionId> {
+		self.labeled("CREATE", name, pass)
+	}
+	pub fn update_user(&mut self, name: &str, pass: &str) -> Result<()> {
+		self.request("UPDATE", &[name, pass]).map(|_| ())
+	}
+	pub fn delete_user(&mut self, name: &str) -> Result<()> {
+		self.request("DELETE", &[name]).map(|_| ())
+	}
+	pub fn unlock_user(&mut self, name: &str) -> Result<()> {
+		self.request("UNLOCK", &[name]).map(|_| ())
+	}
+	// The user's live sessions, oldest first.
+	pub fn list_sessions(&mut self, name: &str) -> Result<Vec<SessionId>> {
+		let result = self.request("LIST", &[name])?;
+		Ok(result.split_whitespace().map(|id| SessionId(id.to_string())).collect())
+	}
+	// Ends every session of the user and returns how many there were.
+	pub fn revoke_sessions(&mut self, name: &str) -> Result<usize> {
+		let result = self.value("REVOKE-ALL", &[name])?;
+		result.parse::<usize>().map_err(|_| Error::Protocol(result))
+	}
+	// Attributes are small values kept with a session until it ends. Reading
+	// or changing them counts as a use of the session.
+	pub fn set_attribute(&mut self, session_id: &str, key: &str, value: &str) -> Result<()> {
+		self.request("SET", &[session_id, key, value]).map(|_| ())
+	}
+	pub fn get_attribute(&mut self, session_id: &str, key: &str) -> Result<String> {
+		let result = self.value("GET", &[session_id, key])?;
+		match split(result.as_str()) {
+			Ok(ref words) if words.len() == 1 => Ok(words[0].clone()),
+			_ => Err(Error::Protocol(result)),
+		}
+	}
+	pub fn delete_attribute(&mut self, session_id: &str, key: &str) -> Result<()> {
+		self.request("DEL", &[session_id, key]).map(|_| ())
+	}
+	// The session's attribute keys in sorted order.
+	pub fn attribute_keys(&mut self, session_id: &str) -> Result<Vec<String>> {
+		let result = self.request("KEYS", &[session_id])?;
+		split(result.as_str()).map_err(|_| Error::Protocol(result))
+	}
+	pub fn save(&mut self) -> Result<()> {
+		self.request("SAVE", &[]).map(|_| ())
+	}
+}
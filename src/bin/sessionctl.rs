@@ -0,0 +1,378 @@
+#[macro_use]
+extern crate serde_json;
+extern crate sessiond;
+extern crate sessiond_client;
+
+use std::collections::HashSet;
+use std::env;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::{self, BufWriter};
+use std::process;
+use std::str;
+use std::time::Duration;
+
+use serde_json::Value;
+use sessiond_client::Client;
+
+use sessiond::{cdb, config, journal};
+use sessiond::config::dir_file;
+
+// Records written before lockouts were counted lack the last field, which
+// sessiond reads as 0.
+const USER_FIELDS: &[&str] = &["password", "created", "updated", "deleted", "last_loggedin", "failed", "fail_count", "locked", "lockouts"];
+
+const USAGE: &str = "usage: sessionctl [--config FILE] [--socket PATH] [--dir DIR] [--json] [--timeout SECS] COMMAND [ARGS]
+
+commands sent to a running daemon (over --socket):
+  auth NAME PASS, login NAME PASS, session ID, info ID, logout ID,
+  create NAME PASS, update NAME PASS, delete NAME, unlock NAME, save,
+  list NAME, revoke-all NAME,
+  set ID KEY VALUE, get ID KEY, del ID KEY, keys ID
+
+offline commands (on --dir, with the daemon stopped):
+  dump [FILE], import FILE, verify, unlock NAME (when --dir or --config is given)
+
+--config reads the daemon's configuration file for its socket, directory,
+users file names and username policy; --socket and --dir override it.";
+
+struct Options {
+	config: config::Config,
+	// Whether --dir or --config said where the data directory is.
+	local: bool,
+	json: bool,
+	timeout: Option<Duration>,
+	args: Vec<String>,
+}
+
+// Human-readable text and the JSON object reported for a successful command.
+struct Reply {
+	text: String,
+	json: Value,
+}
+
+fn reply(text: &str, json: Value) -> Reply {
+	Reply {
+		text: text.to_string(),
+		json,
+	}
+}
+
+fn parse_args() -> Result<Options, String> {
+	let mut path_config: Option<String> = None;
+	let mut overrides: Vec<(String, String)> = Vec::new();
+	let mut options = Options {
+		config: config::Config::default(),
+		local: false,
+		json: false,
+		timeout: None,
+		args: Vec::new(),
+	};
+	let mut args = env::args().skip(1);
+	while let Some(arg) = args.next() {
+		if !options.args.is_empty() {
+			options.args.push(arg);
+			continue;
+		}
+		match arg.as_str() {
+			"--config" => path_config = Some(args.next().ok_or(format!("{}: missing value", arg))?),
+			"--socket" | "-sock" => overrides.push((String::from("socket"), args.next().ok_or(format!("{}: missing value", arg))?)),
+			"--dir" | "-dir" => {
+				overrides.push((String::from("dir"), args.next().ok_or(format!("{}: missing value", arg))?));
+				options.local = true;
+			},
+			"--json" => options.json = true,
+			"--timeout" => {
+				let value = args.next().ok_or(format!("{}: missing value", arg))?;
+				let secs = value.parse::<u64>().map_err(|_| format!("{}: invalid number \"{}\"", arg, value))?;
+				options.timeout = Some(Duration::from_secs(secs));
+			},
+			"--help" | "-h" => return Err(String::from(USAGE)),
+			_ if arg.starts_with('-') => return Err(format!("unknown option {}\n{}", arg, USAGE)),
+			_ => options.args.push(arg),
+		}
+	}
+	if options.args.is_empty() {
+		return Err(String::from(USAGE));
+	}
+	options.local = options.local || path_config.is_some();
+	options.config = config::build(path_config.as_deref(), overrides)?;
+	Ok(options)
+}
+
+fn user_json(name: &str, record: &str) -> Value {
+	let mut user = json!({ "name": name });
+	let mut values = record.split_whitespace();
+	for field in USER_FIELDS.iter() {
+		user[*field] = match values.next() {
+			Some(value) if *field == "password" => json!(value),
+			Some(value) => value.parse::<i64>().map_or(json!(value), |n| json!(n)),
+			None => json!(0),
+		};
+	}
+	user
+}
+
+fn online(options: &Options) -> Result<Reply, String> {
+	let socket = options.config.socket.clone();
+	let mut client = Client::connect_timeout(socket.as_str(), options.timeout).map_err(|e| format!("{}: {}", socket, e))?;
+	let args: Vec<&str> = options.args.iter().map(String::as_str).collect();
+	let result = match args[..] {
+		["auth", name, pass] => client.auth(name, pass).map(|_| reply("OK", json!({}))),
+		["login", name, pass] => client.login(name, pass).map(|id| reply(&id, json!({ "session_id": &*id }))),
+		["session", id] => client.session(id).map(|name| reply(&name, json!({ "name": &*name }))),
+		["info", id] => client.session_info(id).map(|info| {
+			let text = format!("name: {}\ncreated: {}\nlast accessed: {}\nmethod: {}\nsource: {}\nlast source: {}\nlabel: {}",
+				info.name, info.created, info.last_accessed, info.method, info.source, info.last_source, info.label);
+			reply(&text, json!({
+				"name": &*info.name,
+				"created": info.created,
+				"last_accessed": info.last_accessed,
+				"method": info.method,
+				"source": info.source,
+				"last_source": info.last_source,
+				"label": info.label,
+			}))
+		}),
+		["logout", id] => client.logout(id).map(|name| reply(&name, json!({ "name": &*name }))),
+		["create", name, pass] => client.create_user(name, pass).map(|id| reply(&id, json!({ "session_id": &*id }))),
+		["update", name, pass] => client.update_user(name, pass).map(|_| reply("OK", json!({}))),
+		["delete", name] => client.delete_user(name).map(|_| reply("OK", json!({}))),
+		["unlock", name] => client.unlock_user(name).map(|_| reply("OK", json!({}))),
+		["save"] => client.save().map(|_| reply("OK", json!({}))),
+		["list", name] => client.list_sessions(name).map(|ids| {
+			let ids: Vec<&str> = ids.iter().map(|id| &**id).collect();
+			reply(&ids.join("\n"), json!({ "sessions": ids }))
+		}),
+		["revoke-all", name] => client.revoke_sessions(name).map(|n| reply(&n.to_string(), json!({ "revoked": n }))),
+		["set", id, key, value] => client.set_attribute(id, key, value).map(|_| reply("OK", json!({}))),
+		["get", id, key] => client.get_attribute(id, key).map(|value| reply(&value, json!({ "value": value }))),
+		["del", id, key] => client.delete_attribute(id, key).map(|_| reply("OK", json!({}))),
+		["keys", id] => client.attribute_keys(id).map(|keys| reply(&keys.join("\n"), json!({ "keys": keys }))),
+		_ => return Err(String::from(USAGE)),
+	};
+	result.map_err(|e| e.to_string())
+}
+
+// Offline changes must not race a running daemon, so they take the same
+// directory lock sessiond holds while it runs.
+fn lock_dir(dir: &str) -> Result<File, String> {
+	config::lock_dir(dir, "sessiond is running; stop it or use --socket")
+}
+
+fn open_cdb(path: &str) -> Result<Option<cdb::Cdb>, String> {
+	cdb::Cdb::open_if_exists(path).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn read_journal(path: &str) -> Result<(Vec<cdb::Record>, bool), String> {
+	journal::read(path).map_err(|e| format!("{}: {}", path, e))
+}
+
+// The users as the daemon's next save would write them: users.cdb with the
+// journal replayed on top the way sessiond replays it. Each entry is (name,
+// record, created since the last save).
+fn load_users(config: &config::Config) -> Result<Vec<(String, String, bool)>, String> {
+	let dir = config.dir.as_str();
+	let path_cdb = dir_file(dir, config.users_cdb.as_str());
+	let mut users: Vec<(String, String, bool)> = Vec::new();
+	let mut stored: HashSet<String> = HashSet::new();
+	if let Some(cdb) = open_cdb(path_cdb.as_str())? {
+		for record in cdb.iter().map_err(|e| format!("{}: {}", path_cdb, e))? {
+			let (key, val) = record.map_err(|e| format!("{}: {}", path_cdb, e))?;
+			let name = String::from_utf8_lossy(key).into_owned();
+			if stored.insert(name.clone()) {
+				users.push((name, String::from_utf8_lossy(val).into_owned(), false));
+			}
+		}
+	}
+	let (records, _) = read_journal(dir_file(dir, config.users_journal.as_str()).as_str())?;
+	let mut pending = journal::replay(records, |name| stored.contains(name));
+	for user in users.iter_mut() {
+		if let Some(record) = pending.updated.remove(&user.0) {
+			user.1 = record;
+		}
+	}
+	let mut created: Vec<(String, String)> = pending.created.into_iter().collect();
+	created.sort();
+	users.extend(created.into_iter().map(|(name, record)| (name, record, true)));
+	Ok(users)
+}
+
+fn dump(config: &config::Config, out: Option<&str>, json: bool) -> Result<Reply, String> {
+	let users = load_users(config)?;
+	if json {
+		let users: Vec<Value> = users.iter().map(|(name, record, _)| user_json(name, record)).collect();
+		return Ok(reply("", json!({ "users": users })));
+	}
+	let write = |writer: &mut dyn Write| -> io::Result<()> {
+		let mut writer = BufWriter::new(writer);
+		for (name, record, _) in users.iter() {
+			cdb::write_record(&mut writer, name.as_bytes(), record.as_bytes())?;
+		}
+		cdb::write_end(&mut writer)?;
+		writer.flush()
+	};
+	match out {
+		Some(path) => File::create(path).and_then(|mut f| write(&mut f)).map_err(|e| format!("{}: {}", path, e))?,
+		None => write(&mut io::stdout()).map_err(|e| e.to_string())?,
+	}
+	Ok(reply("", json!({})))
+}
+
+// Replaces users.cdb with the dump's contents. Pending journal entries are
+// discarded, since the dump describes the complete user table.
+fn import(config: &config::Config, path: &str) -> Result<Reply, String> {
+	let dir = config.dir.as_str();
+	let _lock = lock_dir(dir)?;
+	let path_tmp = dir_file(dir, config.users_tmp.as_str());
+	let path_cdb = dir_file(dir, config.users_cdb.as_str());
+	cdb::cdb_import(path_tmp.as_str(), path).map_err(|e| format!("{}: {}", path, e))?;
+	let count = cdb::Cdb::open(path_tmp.as_str()).and_then(|cdb| Ok(cdb.iter()?.count()))
+		.map_err(|e| format!("{}: {}", path_tmp, e))?;
+	fs::rename(path_tmp.as_str(), path_cdb.as_str()).map_err(|e| format!("{}: {}", path_cdb, e))?;
+	let path_journal = dir_file(dir, config.users_journal.as_str());
+	let (mut journal, _) = journal::Journal::open(path_journal.as_str()).map_err(|e| format!("{}: {}", path_journal, e))?;
+	journal.clear().map_err(|e| format!("{}: {}", path_journal, e))?;
+	Ok(reply(&format!("imported {} users", count), json!({ "users": count })))
+}
+
+fn check_record(name: &[u8], val: &[u8]) -> Option<String> {
+	let name = String::from_utf8_lossy(name);
+	let fields: Vec<&str> = match str::from_utf8(val) {
+		Ok(val) => val.split_whitespace().collect(),
+		Err(_) => return Some(format!("{}: record is not UTF-8", name)),
+	};
+	if fields.len() != USER_FIELDS.len() && fields.len() != USER_FIELDS.len() - 1 {
+		return Some(format!("{}: expected {} or {} fields, found {}", name, USER_FIELDS.len() - 1, USER_FIELDS.len(), fields.len()));
+	}
+	for (field, value) in USER_FIELDS.iter().zip(fields.iter()).skip(1) {
+		if value.parse::<i64>().is_err() {
+			return Some(format!("{}: {} is not a number", name, field));
+		}
+	}
+	None
+}
+
+fn verify(config: &config::Config) -> Result<Reply, String> {
+	let dir = config.dir.as_str();
+	let mut problems: Vec<String> = Vec::new();
+	let path_cdb = dir_file(dir, config.users_cdb.as_str());
+	let mut users = 0;
+	if let Some(cdb) = open_cdb(path_cdb.as_str())? {
+		for record in cdb.iter().map_err(|e| format!("{}: {}", path_cdb, e))? {
+			let (key, val) = match record {
+				Ok(record) => record,
+				Err(e) => {
+					problems.push(format!("{}: {}", path_cdb, e));
+					break;
+				},
+			};
+			users += 1;
+			match cdb.get(key) {
+				Ok(Some(_)) => {},
+				_ => problems.push(format!("{}: {} is not reachable through the hash table", path_cdb, String::from_utf8_lossy(key))),
+			}
+			if let Some(problem) = check_record(key, val) {
+				problems.push(format!("{}: {}", path_cdb, problem));
+			}
+		}
+	}
+	let path_journal = dir_file(dir, config.users_journal.as_str());
+	let (records, torn) = read_journal(path_journal.as_str())?;
+	if torn {
+		problems.push(format!("{}: torn record at the end (sessiond drops it on start)", path_journal));
+	}
+	for (key, val) in records.iter() {
+		let problem = if let Some(rest) = val.strip_prefix(b"C ").or_else(|| val.strip_prefix(b"U ")) {
+				check_record(key, rest)
+			} else if &val[..] != b"D" {
+				Some(format!("{}: unknown journal entry", String::from_utf8_lossy(key)))
+			} else {
+				None
+			};
+		if let Some(problem) = problem {
+			problems.push(format!("{}: {}", path_journal, problem));
+		}
+	}
+	if !problems.is_empty() {
+		return Err(problems.join("\n"));
+	}
+	Ok(reply(&format!("{} users, {} journal entries: OK", users, records.len()), json!({ "users": users, "journal": records.len() })))
+}
+
+// Clears the lockout through the journal, which the daemon replays when it
+// next starts, so users.cdb itself is left alone.
+fn unlock(config: &config::Config, name: &str) -> Result<Reply, String> {
+	let dir = config.dir.as_str();
+	let _lock = lock_dir(dir)?;
+	// Found the way sessiond finds it: under the normalized name or, for users
+	// stored before names were normalized, under a name that normalizes alike.
+	let policy = config.username_policy();
+	let normalized = policy.normalize(name);
+	let users = load_users(config)?;
+	let (name, record, created) = users.iter().find(|user| user.0 == normalized)
+		.or_else(|| users.iter().find(|user| policy.normalize(user.0.as_str()) == normalized))
+		.ok_or(format!("{}: user not found", name))?;
+	let name = name.as_str();
+	let mut fields: Vec<String> = record.split_whitespace().map(String::from).collect();
+	if fields.len() == USER_FIELDS.len() - 1 {
+		fields.push(String::from("0"));
+	}
+	if fields.len() != USER_FIELDS.len() || fields[3] != "0" {
+		return Err(format!("{}: user not found", name));
+	}
+	for field in fields.iter_mut().skip(6) {
+		*field = String::from("0");
+	}
+	let val = format!("{} {}", if *created { "C" } else { "U" }, fields.join(" "));
+	let path_journal = dir_file(dir, config.users_journal.as_str());
+	let (mut journal, _) = journal::Journal::open(path_journal.as_str()).map_err(|e| format!("{}: {}", path_journal, e))?;
+	journal.append(name.as_bytes(), val.as_bytes()).map_err(|e| format!("{}: {}", path_journal, e))?;
+	Ok(reply("OK", json!({})))
+}
+
+fn offline(options: &Options) -> Option<Result<Reply, String>> {
+	let config = &options.config;
+	let args: Vec<&str> = options.args.iter().map(String::as_str).collect();
+	Some(match args[..] {
+		["dump"] => dump(config, None, options.json),
+		["dump", out] => dump(config, Some(out), options.json),
+		["import", path] => import(config, path),
+		["verify"] => verify(config),
+		["unlock", name] if options.local => unlock(config, name),
+		_ => return None,
+	})
+}
+
+fn main() {
+	let options = match parse_args() {
+		Ok(options) => options,
+		Err(e) => {
+			eprintln!("{}", e);
+			process::exit(2);
+		},
+	};
+	let result = match offline(&options) {
+		Some(result) => result,
+		None => online(&options),
+	};
+	match result {
+		Ok(reply) => {
+			if options.json {
+				let mut json = reply.json;
+				json["ok"] = json!(true);
+				println!("{}", json);
+			} else if !reply.text.is_empty() {
+				println!("{}", reply.text);
+			}
+		},
+		Err(e) => {
+			if options.json {
+				println!("{}", json!({ "ok": false, "error": e }));
+			} else {
+				eprintln!("sessionctl: {}", e);
+			}
+			process::exit(1);
+		},
+	}
+}
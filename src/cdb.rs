@@ -1,14 +1,28 @@
-use std::ffi::{CString, NulError};
-use std::fs::File;
-use std::io::{BufReader, SeekFrom, Error as IoError};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind, SeekFrom, Error as IoError};
 use std::io::prelude::*;
+use std::os::unix::fs::OpenOptionsExt;
 
-use libc::{c_void, c_char, c_uchar, c_int, c_uint, open, close, O_RDONLY, O_RDWR, O_CREAT};
+use memmap2::Mmap;
 
+const HEADER_SIZE: usize = 2048;
+
+pub type Record = (Vec<u8>, Vec<u8>);
+
+#[derive(Debug)]
 pub enum CDBError {
 	Msg(&'static str),
 	Io(IoError),
-	Nul(NulError),
+}
+
+impl fmt::Display for CDBError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			CDBError::Msg(m) => write!(f, "{}", m),
+			CDBError::Io(ref e) => write!(f, "{}", e),
+		}
+	}
 }
 
 impl From<IoError> for CDBError {
@@ -17,139 +31,314 @@ impl From<IoError> for CDBError {
 	}
 }
 
-impl From<NulError> for CDBError {
-	fn from(e: NulError) -> CDBError {
-		CDBError::Nul(e)
-	}
-}
-
-#[repr(C)]
-struct CDB {
-	cdb_fd: c_int,
-	cdb_fsize: c_uint,
-	cdb_dend: c_uint,
-	cdb_mem: *mut c_uchar,
-	cdb_vpos: c_uint,
-	cdb_vlen: c_uint,
-	cdb_kpos: c_uint,
-	cdb_klen: c_uint,
-}
-
-#[repr(C)]
-struct CDB_make {
-	cdb_fd: c_int,
-	cdb_dpos: c_uint,
-	cdb_rcnt: c_uint,
-	cdb_buf: [c_uchar; 4096],
-	cdb_bpos: *mut c_uchar,
-	cdb_rec: *mut c_void,
-}
-
-#[link(name="cdb")]
-extern {
-	fn cdb_init(cdb: *mut CDB, fd: c_int) -> c_int;
-	fn cdb_find(cdb: *mut CDB, key: *const c_char, klen: c_uint) -> c_int;
-	fn cdb_read(cdb: *const CDB, buf: *mut c_void, len: c_uint, pos: c_uint) -> c_int;
-	fn cdb_free(cdb: *mut CDB);
-	fn cdb_unpack(buf: *const c_uchar) -> c_uint;
-	fn cdb_make_start(cdb_make: *mut CDB_make, fd: c_int);
-	fn cdb_make_add(cdb_make: *mut CDB_make, key: *const c_void, klen: c_uint, val: *const c_void, vlen: c_uint);
-	fn cdb_make_finish(cdb_make: *mut CDB_make);
-}
-
-pub fn cdb_get(path: &str, key: &str) -> Result<String, CDBError> {
-	unsafe {
-		let mut result: Option<String> = None;
-		let mut cdb = CDB {
-			cdb_fd: 0,
-			cdb_fsize: 0,
-			cdb_dend: 0,
-			cdb_mem: 0 as *mut c_uchar,
-			cdb_vpos: 0,
-			cdb_vlen: 0,
-			cdb_kpos: 0,
-			cdb_klen: 0,
-		};
-		let cpath = CString::new(path)?;
-		let ckey = CString::new(key)?;
-		let fd = open(cpath.as_ptr(), O_RDONLY);
-		cdb_init(&mut cdb, fd);
-		if cdb_find(&mut cdb, ckey.as_ptr(), key.len() as c_uint) > 0 {
-			let mut buf: Vec<u8> = Vec::with_capacity(cdb.cdb_vlen as usize);
-			if cdb_read(&cdb, buf.as_mut_ptr() as *mut c_void, cdb.cdb_vlen, cdb.cdb_vpos) == 0 {
-				buf.set_len(cdb.cdb_vlen as usize);
-				if let Ok(val) = String::from_utf8(buf) {
-					result = Some(val);
-				}
-			}
+fn hash(key: &[u8]) -> u32 {
+	let mut h: u32 = 5381;
+	for b in key {
+		h = (h << 5).wrapping_add(h) ^ u32::from(*b);
+	}
+	h
+}
+
+fn unpack(buf: &[u8], pos: usize) -> Result<u32, CDBError> {
+	match buf.get(pos..pos + 4) {
+		Some(b) => Ok(u32::from(b[0]) | u32::from(b[1]) << 8 | u32::from(b[2]) << 16 | u32::from(b[3]) << 24),
+		None => Err(CDBError::Msg("CDB corrupted.")),
+	}
+}
+
+fn pack(n: u32) -> [u8; 4] {
+	[n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]
+}
+
+pub struct Cdb {
+	data: Mmap,
+}
+
+impl Cdb {
+	pub fn open(path: &str) -> Result<Cdb, CDBError> {
+		let file = File::open(path)?;
+		// A CDB is never modified in place: a new one is built aside and renamed
+		// over the old path, so the mapping stays valid for the handle's lifetime.
+		let data = unsafe { Mmap::map(&file)? };
+		if data.len() < HEADER_SIZE {
+			return Err(CDBError::Msg("CDB corrupted."));
+		}
+		Ok(Cdb { data })
+	}
+	// Like open, but a missing file is an empty database rather than an
+	// error; the next save creates it.
+	pub fn open_if_exists(path: &str) -> Result<Option<Cdb>, CDBError> {
+		match Cdb::open(path) {
+			Ok(cdb) => Ok(Some(cdb)),
+			Err(CDBError::Io(ref e)) if e.kind() == ErrorKind::NotFound => Ok(None),
+			Err(e) => Err(e),
 		}
-		cdb_free(&mut cdb);
-		close(fd);
-		result.ok_or(CDBError::Msg("CDB Failed."))
-	}
-}
-
-pub fn cdb_export(cdb_path: &str, out_path: &str) -> Result<(), CDBError> {
-	unsafe {
-		let mut buf: [u8; 2048] = [0; 2048];
-		let mut fin = File::open(cdb_path)?;
-		let mut fout = File::create(out_path)?;
-		let mut pos: usize = 2048;
-		fin.read(&mut buf[..4])?;
-		let eod: usize = cdb_unpack(buf[..4].as_mut_ptr()) as usize;
-		fin.seek(SeekFrom::Start(pos as u64))?;
-		while pos < eod {
-			fin.read(&mut buf[..4])?;
-			pos += 4;
-			let klen = cdb_unpack(buf[..4].as_mut_ptr()) as usize;
-			fin.read(&mut buf[..4])?;
-			pos += 4;
-			let vlen = cdb_unpack(buf[..4].as_mut_ptr()) as usize;
-			if klen <= 2048 {
-				fin.read(&mut buf[..klen])?;
-				fout.write(&buf[..klen])?;
-				pos += klen;
+	}
+	fn record(&self, pos: usize) -> Result<(&[u8], &[u8], usize), CDBError> {
+		let klen = unpack(&self.data, pos)? as usize;
+		let vlen = unpack(&self.data, pos + 4)? as usize;
+		let kpos = pos + 8;
+		let vpos = kpos + klen;
+		match (self.data.get(kpos..vpos), self.data.get(vpos..vpos + vlen)) {
+			(Some(key), Some(val)) => Ok((key, val, vpos + vlen)),
+			_ => Err(CDBError::Msg("CDB corrupted.")),
+		}
+	}
+	pub fn get(&self, key: &[u8]) -> Result<Option<&[u8]>, CDBError> {
+		let h = hash(key);
+		let table = (h as usize & 255) * 8;
+		let tpos = unpack(&self.data, table)? as usize;
+		let tlen = unpack(&self.data, table + 4)? as usize;
+		if tlen == 0 {
+			return Ok(None);
+		}
+		let start = (h >> 8) as usize % tlen;
+		for i in 0..tlen {
+			let slot = tpos + (start + i) % tlen * 8;
+			let shash = unpack(&self.data, slot)?;
+			let rpos = unpack(&self.data, slot + 4)? as usize;
+			if rpos == 0 {
+				return Ok(None);
 			}
-			fout.write(b"\x20")?;
-			if vlen <= 2048 {
-				fin.read(&mut buf[..vlen])?;
-				fout.write(&buf[..vlen])?;
-				pos += vlen;
+			if shash == h {
+				let (k, v, _) = self.record(rpos)?;
+				if k == key {
+					return Ok(Some(v));
+				}
 			}
-			fout.write(b"\n")?;
 		}
+		Ok(None)
+	}
+	pub fn export(&self, out_path: &str) -> Result<(), CDBError> {
+		let mut fout = BufWriter::new(File::create(out_path)?);
+		for record in self.iter()? {
+			let (key, val) = record?;
+			write_record(&mut fout, key, val)?;
+		}
+		write_end(&mut fout)?;
+		fout.flush()?;
 		Ok(())
 	}
+	pub fn iter(&self) -> Result<Iter<'_>, CDBError> {
+		Ok(Iter {
+			cdb: self,
+			pos: HEADER_SIZE,
+			eod: unpack(&self.data, 0)? as usize,
+		})
+	}
 }
 
-pub fn cdb_import(cdb_path: &str, in_path: &str) -> Result<(), CDBError> {
-	unsafe {
-		let mut cdb_make = CDB_make {
-			cdb_fd: 0,
-			cdb_dpos: 0,
-			cdb_rcnt: 0,
-			cdb_buf: [0; 4096],
-			cdb_bpos: 0 as *mut c_uchar,
-			cdb_rec: 0 as *mut c_void,
-		};
-		let cpath = CString::new(cdb_path)?;
-		let fd = open(cpath.as_ptr(), O_RDWR | O_CREAT, 0o660);
-		cdb_make_start(&mut cdb_make, fd);
-		let fin = File::open(in_path)?;
-		let reader = BufReader::new(fin);
-		for line in reader.lines() {
-			if let Ok(line) = line {
-				if let Some(pos) = line.find(char::is_whitespace) {
-					let key = &line[..pos];
-					let val = &line[pos + 1..];
-					let ckey = CString::new(key)?;
-					let cval = CString::new(val)?;
-					cdb_make_add(&mut cdb_make, ckey.as_ptr() as *const c_void, key.len() as u32, cval.as_ptr() as *const c_void, val.len() as u32);
+pub struct Iter<'a> {
+	cdb: &'a Cdb,
+	pos: usize,
+	eod: usize,
+}
+
+impl<'a> Iterator for Iter<'a> {
+	type Item = Result<(&'a [u8], &'a [u8]), CDBError>;
+	fn next(&mut self) -> Option<Self::Item> {
+		if self.pos >= self.eod {
+			return None;
+		}
+		match self.cdb.record(self.pos) {
+			Ok((key, val, next)) => {
+				self.pos = next;
+				Some(Ok((key, val)))
+			},
+			Err(e) => {
+				self.pos = self.eod;
+				Some(Err(e))
+			},
+		}
+	}
+}
+
+pub struct CdbMake {
+	writer: BufWriter<File>,
+	pos: u32,
+	records: Vec<(u32, u32)>,
+}
+
+impl CdbMake {
+	pub fn create(path: &str) -> Result<CdbMake, CDBError> {
+		let file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o660).open(path)?;
+		let mut writer = BufWriter::new(file);
+		writer.write_all(&[0; HEADER_SIZE])?;
+		Ok(CdbMake {
+			writer,
+			pos: HEADER_SIZE as u32,
+			records: Vec::new(),
+		})
+	}
+	fn advance(&mut self, len: usize) -> Result<(), CDBError> {
+		if len > u32::MAX as usize {
+			return Err(CDBError::Msg("CDB too large."));
+		}
+		self.pos = self.pos.checked_add(len as u32).ok_or(CDBError::Msg("CDB too large."))?;
+		Ok(())
+	}
+	pub fn add(&mut self, key: &[u8], val: &[u8]) -> Result<(), CDBError> {
+		let pos = self.pos;
+		self.advance(8)?;
+		self.advance(key.len())?;
+		self.advance(val.len())?;
+		self.writer.write_all(&pack(key.len() as u32))?;
+		self.writer.write_all(&pack(val.len() as u32))?;
+		self.writer.write_all(key)?;
+		self.writer.write_all(val)?;
+		self.records.push((hash(key), pos));
+		Ok(())
+	}
+	pub fn finish(mut self) -> Result<(), CDBError> {
+		let mut header: Vec<u8> = Vec::with_capacity(HEADER_SIZE);
+		for t in 0..256 {
+			let entries: Vec<&(u32, u32)> = self.records.iter().filter(|&&(h, _)| h as usize & 255 == t).collect();
+			let tlen = entries.len() * 2;
+			let mut slots: Vec<(u32, u32)> = vec![(0, 0); tlen];
+			for &&(h, pos) in entries.iter() {
+				let mut i = (h >> 8) as usize % tlen;
+				while slots[i].1 != 0 {
+					i = (i + 1) % tlen;
 				}
+				slots[i] = (h, pos);
+			}
+			header.extend_from_slice(&pack(self.pos));
+			header.extend_from_slice(&pack(tlen as u32));
+			for (h, pos) in slots {
+				self.writer.write_all(&pack(h))?;
+				self.writer.write_all(&pack(pos))?;
 			}
+			self.advance(tlen * 8)?;
 		}
-		cdb_make_finish(&mut cdb_make);
-		close(fd);
+		self.writer.flush()?;
+		let mut file = self.writer.into_inner().map_err(|e| CDBError::Io(e.into_error()))?;
+		file.seek(SeekFrom::Start(0))?;
+		file.write_all(&header)?;
+		file.sync_all()?;
 		Ok(())
 	}
 }
+
+// Dumps use the cdbmake format: "+klen,vlen:key->val\n" per record and a blank
+// line at the end. Lengths are explicit, so any bytes survive a round trip.
+pub fn write_record<W: Write>(writer: &mut W, key: &[u8], val: &[u8]) -> Result<(), IoError> {
+	write!(writer, "+{},{}:", key.len(), val.len())?;
+	writer.write_all(key)?;
+	writer.write_all(b"->")?;
+	writer.write_all(val)?;
+	writer.write_all(b"\n")
+}
+
+pub fn write_end<W: Write>(writer: &mut W) -> Result<(), IoError> {
+	writer.write_all(b"\n")
+}
+
+fn read_len<R: BufRead>(reader: &mut R, delim: u8) -> Result<usize, CDBError> {
+	let mut buf = Vec::new();
+	reader.read_until(delim, &mut buf)?;
+	if buf.pop() != Some(delim) {
+		return Err(CDBError::Msg("Dump truncated."));
+	}
+	match String::from_utf8(buf).ok().and_then(|s| s.parse::<u32>().ok()) {
+		Some(len) => Ok(len as usize),
+		None => Err(CDBError::Msg("Dump corrupted.")),
+	}
+}
+
+fn read_exact<R: BufRead>(reader: &mut R, len: usize) -> Result<Vec<u8>, CDBError> {
+	let mut buf = Vec::new();
+	reader.take(len as u64).read_to_end(&mut buf)?;
+	if buf.len() != len {
+		return Err(CDBError::Msg("Dump truncated."));
+	}
+	Ok(buf)
+}
+
+pub fn read_record<R: BufRead>(reader: &mut R) -> Result<Option<Record>, CDBError> {
+	match read_exact(reader, 1)?[0] {
+		b'+' => {},
+		b'\n' => return Ok(None),
+		_ => return Err(CDBError::Msg("Dump corrupted.")),
+	}
+	let klen = read_len(reader, b',')?;
+	let vlen = read_len(reader, b':')?;
+	let key = read_exact(reader, klen)?;
+	if read_exact(reader, 2)? != b"->" {
+		return Err(CDBError::Msg("Dump corrupted."));
+	}
+	let val = read_exact(reader, vlen)?;
+	if read_exact(reader, 1)? != b"\n" {
+		return Err(CDBError::Msg("Dump corrupted."));
+	}
+	Ok(Some((key, val)))
+}
+
+pub fn cdb_import(cdb_path: &str, in_path: &str) -> Result<(), CDBError> {
+	let mut cdb_make = CdbMake::create(cdb_path)?;
+	let mut reader = BufReader::new(File::open(in_path)?);
+	while let Some((key, val)) = read_record(&mut reader)? {
+		cdb_make.add(&key, &val)?;
+	}
+	cdb_make.finish()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::env;
+	use std::fs;
+	use std::process;
+
+	fn temp_path(name: &str) -> String {
+		let mut path = env::temp_dir();
+		path.push(format!("sessiond-cdb-{}-{}", process::id(), name));
+		path.to_str().unwrap().to_string()
+	}
+
+	#[test]
+	fn export_import_round_trip() {
+		let records: Vec<Record> = vec![
+			(b"alice".to_vec(), b"pass 1 2 3".to_vec()),
+			(b"".to_vec(), b"empty key".to_vec()),
+			(b"empty value".to_vec(), b"".to_vec()),
+			(b"nul\0key".to_vec(), b"nul\0value\n+1,1:x->y\n".to_vec()),
+			(vec![0xff, 0xfe, 0x20], vec![0x80; 3]),
+			(vec![b'k'; 3000], vec![b'v'; 100000]),
+			(b"dup".to_vec(), b"first".to_vec()),
+			(b"dup".to_vec(), b"second".to_vec()),
+		];
+		let path_cdb = temp_path("rt.cdb");
+		let path_dump = temp_path("rt.dump");
+		let path_copy = temp_path("rt.copy");
+
+		let mut cdb_make = CdbMake::create(path_cdb.as_str()).unwrap();
+		for (key, val) in records.iter() {
+			cdb_make.add(key, val).unwrap();
+		}
+		cdb_make.finish().unwrap();
+		Cdb::open(path_cdb.as_str()).unwrap().export(path_dump.as_str()).unwrap();
+		cdb_import(path_copy.as_str(), path_dump.as_str()).unwrap();
+
+		let cdb = Cdb::open(path_copy.as_str()).unwrap();
+		let loaded: Vec<Record> = cdb.iter().unwrap().map(|r| {
+			let (k, v) = r.unwrap();
+			(k.to_vec(), v.to_vec())
+		}).collect();
+		assert_eq!(loaded, records);
+		for (key, val) in records.iter().filter(|(k, _)| k != b"dup") {
+			assert_eq!(cdb.get(key).unwrap(), Some(&val[..]));
+		}
+		assert_eq!(cdb.get(b"dup").unwrap(), Some(&b"first"[..]));
+		assert_eq!(cdb.get(b"missing").unwrap(), None);
+
+		for path in [path_cdb, path_dump, path_copy].iter() {
+			let _ = fs::remove_file(path);
+		}
+	}
+
+	#[test]
+	fn truncated_dump_is_rejected() {
+		let mut reader: &[u8] = b"+3,5:key->val";
+		assert!(read_record(&mut reader).is_err());
+	}
+}
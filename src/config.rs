@@ -0,0 +1,630 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::prelude::*;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+
+use toml;
+use unicode_normalization::UnicodeNormalization;
+
+const LOCK_COUNT: u64 = 5;
+const LOCK_DURATION: i64 = 0;
+const LOCK_GROWTH: i64 = 1;
+const LOCK_DURATION_MAX: i64 = 0;
+const SESSION_PERIOD: i64 = 3600;
+const SESSION_LIFETIME: i64 = 0;
+const MAINTENANCE_INTERVAL: u64 = 600;
+const ATTRIBUTE_KEY_MAX: usize = 256;
+const ATTRIBUTE_VALUE_MAX: usize = 4096;
+const ATTRIBUTE_TOTAL_MAX: usize = 65536;
+const REQUEST_MAX: usize = 65536;
+const TCP_TIMEOUT: u64 = 300;
+const HTTP_ADMIN_TOKEN_MIN: usize = 16;
+// What a client reaching the daemon over the network may run unless the ACL
+// says otherwise: everything but user administration and SAVE.
+const ACL_NETWORK: &[&str] = &["AUTH", "LOGIN", "SESSION", "SESSIONINFO", "LOGOUT", "SET", "GET", "DEL", "KEYS"];
+const USERNAME_MIN_LENGTH: usize = 1;
+const USERNAME_MAX_LENGTH: usize = 64;
+const USERNAME_CHARS: &str = "._-@";
+const FILE_SOCKET: &str = "sessiond.sock";
+pub const FILE_LOCK: &str = "sessiond.lock";
+const FILE_USERS_CDB: &str = "users.cdb";
+const FILE_USERS_OLD: &str = "users.old";
+const FILE_USERS_NEW: &str = "users.new";
+const FILE_USERS_TMP: &str = "users.tmp";
+const FILE_USERS_JOURNAL: &str = "users.journal";
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionIdEncoding {
+	Hex,
+	Base64url,
+}
+
+// What a login does when the user already holds `session_limit` sessions.
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SessionLimitPolicy {
+	Reject,
+	EvictOldest,
+}
+
+// Commands a connecting process may run, keyed by its uid or gid as seen
+// through SO_PEERCRED. A peer gets the union of the lists matching its uid and
+// gid, or the default list if neither matches. "*" allows every command.
+// TLS clients are matched the same way by the DNS names of their verified
+// certificate, listed under `cert`, and fall back to the network list instead,
+// as do HTTP requests. HTTP requests that bear the admin token get the
+// http_admin list.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Acl {
+	pub default: Vec<String>,
+	pub network: Vec<String>,
+	pub http_admin: Vec<String>,
+	pub uid: HashMap<String, Vec<String>>,
+	pub gid: HashMap<String, Vec<String>>,
+	pub cert: HashMap<String, Vec<String>>,
+}
+
+impl Default for Acl {
+	fn default() -> Acl {
+		Acl {
+			default: vec![String::from("*")],
+			network: ACL_NETWORK.iter().map(|c| c.to_string()).collect(),
+			http_admin: vec![String::from("*")],
+			uid: HashMap::new(),
+			gid: HashMap::new(),
+			cert: HashMap::new(),
+		}
+	}
+}
+
+impl Acl {
+	pub fn allows(&self, uid: Option<u32>, gid: Option<u32>, cmd: &str) -> bool {
+		let by_uid = uid.and_then(|uid| self.uid.get(&uid.to_string()));
+		let by_gid = gid.and_then(|gid| self.gid.get(&gid.to_string()));
+		self.check(by_uid.into_iter().chain(by_gid).collect(), &self.default, cmd)
+	}
+	pub fn allows_network(&self, names: &[String], cmd: &str) -> bool {
+		self.check(names.iter().filter_map(|name| self.cert.get(name)).collect(), &self.network, cmd)
+	}
+	pub fn allows_http_admin(&self, cmd: &str) -> bool {
+		self.check(Vec::new(), &self.http_admin, cmd)
+	}
+	fn check<'a>(&'a self, lists: Vec<&'a Vec<String>>, fallback: &'a Vec<String>, cmd: &str) -> bool {
+		let lists = if lists.is_empty() { vec![fallback] } else { lists };
+		lists.iter().any(|list| list.iter().any(|c| c == "*" || c == cmd))
+	}
+	fn validate(&self) -> Result<(), String> {
+		for key in self.uid.keys().chain(self.gid.keys()) {
+			if key.parse::<u32>().is_err() {
+				return Err(format!("acl: \"{}\" is not a numeric uid or gid", key));
+			}
+		}
+		Ok(())
+	}
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+	pub socket: String,
+	pub dir: String,
+	pub lock_count: u64,
+	pub lock_duration: i64,
+	pub lock_growth: i64,
+	pub lock_duration_max: i64,
+	pub session_period: i64,
+	pub session_lifetime: i64,
+	pub session_id_encoding: SessionIdEncoding,
+	pub session_limit: usize,
+	pub session_limit_policy: SessionLimitPolicy,
+	pub revoke_on_password_change: bool,
+	pub revoke_on_delete: bool,
+	// Byte limits on session attributes: each key, each value, and all keys
+	// and values of one session together.
+	pub attribute_key_max: usize,
+	pub attribute_value_max: usize,
+	pub attribute_total_max: usize,
+	// Longest request line a client may send, and how long a TCP connection
+	// may sit idle, in seconds.
+	pub request_max: usize,
+	pub tcp_timeout: u64,
+	pub maintenance_interval: u64,
+	pub username_min_length: usize,
+	pub username_max_length: usize,
+	pub username_chars: String,
+	pub username_ascii: bool,
+	pub username_case_fold: bool,
+	pub username_nfc: bool,
+	pub users_cdb: String,
+	pub users_old: String,
+	pub users_new: String,
+	pub users_tmp: String,
+	pub users_journal: String,
+	pub tcp_listen: String,
+	pub tls_cert: String,
+	pub tls_key: String,
+	pub tls_client_ca: String,
+	// The HTTP API is plain text: passwords, session IDs and the admin token
+	// cross the network unencrypted, so it belongs on loopback or behind a
+	// TLS-terminating proxy. Requests sent with "Authorization: Bearer
+	// <http_admin_token>" may run the commands in acl.http_admin; an empty
+	// token turns that off.
+	pub http_listen: String,
+	pub http_admin_token: String,
+	pub acl: Acl,
+}
+
+impl Default for Config {
+	fn default() -> Config {
+		Config {
+			socket: String::from(FILE_SOCKET),
+			dir: String::new(),
+			lock_count: LOCK_COUNT,
+			lock_duration: LOCK_DURATION,
+			lock_growth: LOCK_GROWTH,
+			lock_duration_max: LOCK_DURATION_MAX,
+			session_period: SESSION_PERIOD,
+			session_lifetime: SESSION_LIFETIME,
+			session_id_encoding: SessionIdEncoding::Hex,
+			session_limit: 0,
+			session_limit_policy: SessionLimitPolicy::Reject,
+			revoke_on_password_change: false,
+			revoke_on_delete: false,
+			attribute_key_max: ATTRIBUTE_KEY_MAX,
+			attribute_value_max: ATTRIBUTE_VALUE_MAX,
+			attribute_total_max: ATTRIBUTE_TOTAL_MAX,
+			request_max: REQUEST_MAX,
+			tcp_timeout: TCP_TIMEOUT,
+			maintenance_interval: MAINTENANCE_INTERVAL,
+			username_min_length: USERNAME_MIN_LENGTH,
+			username_max_length: USERNAME_MAX_LENGTH,
+			username_chars: String::from(USERNAME_CHARS),
+			username_ascii: false,
+			username_case_fold: true,
+			username_nfc: true,
+			users_cdb: String::from(FILE_USERS_CDB),
+			users_old: String::from(FILE_USERS_OLD),
+			users_new: String::from(FILE_USERS_NEW),
+			users_tmp: String::from(FILE_USERS_TMP),
+			users_journal: String::from(FILE_USERS_JOURNAL),
+			tcp_listen: String::new(),
+			tls_cert: String::new(),
+			tls_key: String::new(),
+			tls_client_ca: String::new(),
+			http_listen: String::new(),
+			http_admin_token: String::new(),
+			acl: Acl::default(),
+		}
+	}
+}
+
+// A lockout lasts `duration` seconds, multiplied by `growth` for every earlier
+// lockout since the last successful login and capped at `duration_max`.
+// A zero duration keeps the account locked until an UNLOCK.
+#[derive(Clone, Copy)]
+pub struct LockPolicy {
+	pub count: u64,
+	pub duration: i64,
+	pub growth: i64,
+	pub duration_max: i64,
+}
+
+impl LockPolicy {
+	pub fn duration(&self, lockouts: u64) -> i64 {
+		let mut duration = self.duration;
+		for _ in 1..lockouts {
+			if self.growth == 1 || duration == 0 || duration == i64::MAX || (self.duration_max > 0 && duration >= self.duration_max) {
+				break;
+			}
+			duration = duration.saturating_mul(self.growth);
+		}
+		if self.duration_max > 0 && duration > self.duration_max {
+			self.duration_max
+		} else {
+			duration
+		}
+	}
+	pub fn has_expired(&self, locked: i64, lockouts: u64, now: i64) -> bool {
+		self.duration != 0 && locked.saturating_add(self.duration(lockouts)) <= now
+	}
+}
+
+// Every name is normalized before it is looked up or stored: to NFC and, with
+// `case_fold`, to lower case, so that one account has exactly one spelling.
+// Names of new users must also be `min_length` to `max_length` characters of
+// letters, digits (ASCII ones only with `ascii`) and the characters in `chars`.
+pub struct UsernamePolicy<'a> {
+	pub min_length: usize,
+	pub max_length: usize,
+	pub chars: &'a str,
+	pub ascii: bool,
+	pub case_fold: bool,
+	pub nfc: bool,
+}
+
+impl<'a> UsernamePolicy<'a> {
+	pub fn normalize(&self, name: &str) -> String {
+		let name = if self.case_fold { name.to_lowercase() } else { name.to_string() };
+		if self.nfc {
+			name.nfc().collect()
+		} else {
+			name
+		}
+	}
+	pub fn allows(&self, name: &str) -> bool {
+		let len = name.chars().count();
+		len >= self.min_length && len <= self.max_length && name.chars().all(|c| {
+			(if self.ascii { c.is_ascii_alphanumeric() } else { c.is_alphanumeric() }) || self.chars.contains(c)
+		})
+	}
+}
+
+pub fn dir_file(dir: &str, file: &str) -> String {
+	if !dir.is_empty() {
+		let mut path_buf = PathBuf::from(dir);
+		path_buf.push(file);
+		path_buf.as_path().to_str().unwrap_or(file).to_string()
+	} else {
+		String::from(file)
+	}
+}
+
+// Takes the exclusive lock on the data directory that sessiond holds for the
+// life of the process, so that two daemons, or a daemon and an offline
+// sessionctl change, never share one users.cdb. `busy` says why the lock is
+// already taken.
+pub fn lock_dir(dir: &str, busy: &str) -> Result<File, String> {
+	let path = dir_file(dir, FILE_LOCK);
+	let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).mode(0o660).open(path.as_str())
+		.map_err(|e| format!("{}: {}", path, e))?;
+	match file.try_lock() {
+		Ok(()) => Ok(file),
+		Err(TryLockError::WouldBlock) => Err(format!("{}: {}", path, busy)),
+		Err(TryLockError::Error(e)) => Err(format!("{}: {}", path, e)),
+	}
+}
+
+fn parse_flag(key: &str, value: &str) -> Result<bool, String> {
+	value.parse::<bool>().map_err(|_| format!("{}: expected true or false", key))
+}
+
+fn parse_number<T: ::std::str::FromStr>(key: &str, value: &str) -> Result<T, String> {
+	value.parse::<T>().map_err(|_| format!("{}: invalid number \"{}\"", key, value))
+}
+
+impl Config {
+	fn from_file(path: &str) -> Result<Config, String> {
+		let mut buf = String::new();
+		File::open(path)
+			.and_then(|mut f| f.read_to_string(&mut buf))
+			.map_err(|e| format!("{}: {}", path, e))?;
+		toml::from_str(buf.as_str()).map_err(|e| format!("{}: {}", path, e))
+	}
+	fn set(&mut self, key: &str, value: String) -> Result<(), String> {
+		match key {
+			"socket" => self.socket = value,
+			"dir" => self.dir = value,
+			"lock-count" => self.lock_count = parse_number(key, value.as_str())?,
+			"lock-duration" => self.lock_duration = parse_number(key, value.as_str())?,
+			"lock-growth" => self.lock_growth = parse_number(key, value.as_str())?,
+			"lock-duration-max" => self.lock_duration_max = parse_number(key, value.as_str())?,
+			"session-period" => self.session_period = parse_number(key, value.as_str())?,
+			"session-lifetime" => self.session_lifetime = parse_number(key, value.as_str())?,
+			"session-id-encoding" => {
+				self.session_id_encoding = match value.as_str() {
+					"hex" => SessionIdEncoding::Hex,
+					"base64url" => SessionIdEncoding::Base64url,
+					_ => return Err(format!("{}: expected hex or base64url", key)),
+				};
+			},
+			"session-limit" => self.session_limit = parse_number(key, value.as_str())?,
+			"session-limit-policy" => {
+				self.session_limit_policy = match value.as_str() {
+					"reject" => SessionLimitPolicy::Reject,
+					"evict-oldest" => SessionLimitPolicy::EvictOldest,
+					_ => return Err(format!("{}: expected reject or evict-oldest", key)),
+				};
+			},
+			"revoke-on-password-change" => self.revoke_on_password_change = parse_flag(key, value.as_str())?,
+			"revoke-on-delete" => self.revoke_on_delete = parse_flag(key, value.as_str())?,
+			"attribute-key-max" => self.attribute_key_max = parse_number(key, value.as_str())?,
+			"attribute-value-max" => self.attribute_value_max = parse_number(key, value.as_str())?,
+			"attribute-total-max" => self.attribute_total_max = parse_number(key, value.as_str())?,
+			"request-max" => self.request_max = parse_number(key, value.as_str())?,
+			"tcp-timeout" => self.tcp_timeout = parse_number(key, value.as_str())?,
+			"maintenance-interval" => self.maintenance_interval = parse_number(key, value.as_str())?,
+			"username-min-length" => self.username_min_length = parse_number(key, value.as_str())?,
+			"username-max-length" => self.username_max_length = parse_number(key, value.as_str())?,
+			"username-chars" => self.username_chars = value,
+			"username-ascii" => self.username_ascii = parse_flag(key, value.as_str())?,
+			"username-case-fold" => self.username_case_fold = parse_flag(key, value.as_str())?,
+			"username-nfc" => self.username_nfc = parse_flag(key, value.as_str())?,
+			"users-cdb" => self.users_cdb = value,
+			"users-old" => self.users_old = value,
+			"users-new" => self.users_new = value,
+			"users-tmp" => self.users_tmp = value,
+			"users-journal" => self.users_journal = value,
+			"tcp-listen" => self.tcp_listen = value,
+			"tls-cert" => self.tls_cert = value,
+			"tls-key" => self.tls_key = value,
+			"tls-client-ca" => self.tls_client_ca = value,
+			"http-listen" => self.http_listen = value,
+			"http-admin-token" => self.http_admin_token = value,
+			_ => return Err(format!("unknown option --{}", key)),
+		}
+		Ok(())
+	}
+	pub fn lock_policy(&self) -> LockPolicy {
+		LockPolicy {
+			count: self.lock_count,
+			duration: self.lock_duration,
+			growth: self.lock_growth,
+			duration_max: self.lock_duration_max,
+		}
+	}
+	pub fn username_policy(&self) -> UsernamePolicy<'_> {
+		UsernamePolicy {
+			min_length: self.username_min_length,
+			max_length: self.username_max_length,
+			chars: self.username_chars.as_str(),
+			ascii: self.username_ascii,
+			case_fold: self.username_case_fold,
+			nfc: self.username_nfc,
+		}
+	}
+	fn validate(&self) -> Result<(), String> {
+		if self.lock_count == 0 {
+			return Err(String::from("lock_count must be at least 1"));
+		}
+		if self.lock_duration < 0 || self.lock_duration_max < 0 {
+			return Err(String::from("lock_duration and lock_duration_max must not be negative"));
+		}
+		if self.lock_growth < 1 {
+			return Err(String::from("lock_growth must be at least 1"));
+		}
+		if self.session_period <= 0 {
+			return Err(String::from("session_period must be positive"));
+		}
+		if self.session_lifetime < 0 {
+			return Err(String::from("session_lifetime must not be negative"));
+		}
+		if self.attribute_key_max == 0 {
+			return Err(String::from("attribute_key_max must be at least 1"));
+		}
+		// A SET of the largest attribute allowed must still fit in a request.
+		if self.request_max <= self.attribute_key_max + self.attribute_value_max {
+			return Err(String::from("request_max must be greater than attribute_key_max and attribute_value_max together"));
+		}
+		if self.tcp_timeout == 0 {
+			return Err(String::from("tcp_timeout must be positive"));
+		}
+		if self.maintenance_interval == 0 {
+			return Err(String::from("maintenance_interval must be positive"));
+		}
+		if self.username_min_length == 0 || self.username_max_length < self.username_min_length {
+			return Err(String::from("username_min_length must be at least 1 and at most username_max_length"));
+		}
+		// Names are stored in space-separated records.
+		if self.username_chars.chars().any(|c| c.is_whitespace() || c.is_control()) {
+			return Err(String::from("username_chars must not contain blanks or control characters"));
+		}
+		if self.socket.is_empty() && self.tcp_listen.is_empty() && self.http_listen.is_empty() {
+			return Err(String::from("one of socket, tcp_listen or http_listen must be set"));
+		}
+		if !self.tcp_listen.is_empty() && (self.tls_cert.is_empty() || self.tls_key.is_empty()) {
+			return Err(String::from("tcp_listen requires tls_cert and tls_key"));
+		}
+		// It travels in a header, and a short one is easily guessed.
+		if !self.http_admin_token.is_empty() && (self.http_admin_token.len() < HTTP_ADMIN_TOKEN_MIN || self.http_admin_token.chars().any(|c| c.is_whitespace() || c.is_control())) {
+			return Err(format!("http_admin_token must be at least {} characters without blanks or control characters", HTTP_ADMIN_TOKEN_MIN));
+		}
+		self.acl.validate()?;
+		let files = [
+			&self.users_cdb,
+			&self.users_old,
+			&self.users_new,
+			&self.users_tmp,
+			&self.users_journal,
+		];
+		for (i, file) in files.iter().enumerate() {
+			if file.is_empty() {
+				return Err(String::from("users file names must not be empty"));
+			}
+			if files[..i].contains(file) {
+				return Err(format!("users file name \"{}\" is used twice", file));
+			}
+		}
+		Ok(())
+	}
+	// Settings that name files, sockets or certificates are fixed for the life of the process;
+	// everything else is taken from the reloaded configuration. Returns false
+	// if the reloaded configuration tried to change a fixed setting.
+	pub fn reload(&mut self, mut config: Config) -> bool {
+		let same =
+			self.socket == config.socket &&
+			self.dir == config.dir &&
+			self.users_cdb == config.users_cdb &&
+			self.users_old == config.users_old &&
+			self.users_new == config.users_new &&
+			self.users_tmp == config.users_tmp &&
+			self.users_journal == config.users_journal &&
+			self.tcp_listen == config.tcp_listen &&
+			self.tls_cert == config.tls_cert &&
+			self.tls_key == config.tls_key &&
+			self.tls_client_ca == config.tls_client_ca &&
+			self.http_listen == config.http_listen;
+		config.socket = self.socket.clone();
+		config.dir = self.dir.clone();
+		config.users_cdb = self.users_cdb.clone();
+		config.users_old = self.users_old.clone();
+		config.users_new = self.users_new.clone();
+		config.users_tmp = self.users_tmp.clone();
+		config.users_journal = self.users_journal.clone();
+		config.tcp_listen = self.tcp_listen.clone();
+		config.tls_cert = self.tls_cert.clone();
+		config.tls_key = self.tls_key.clone();
+		config.tls_client_ca = self.tls_client_ca.clone();
+		config.http_listen = self.http_listen.clone();
+		*self = config;
+		same
+	}
+}
+
+// Builds the configuration from the command line: the file named by --config
+// (if any) is read first, then every other flag overrides it. The legacy
+// -sock and -dir flags are still accepted.
+pub fn load() -> Result<Config, String> {
+	let mut path_config: Option<String> = None;
+	let mut overrides: Vec<(String, String)> = Vec::new();
+	let mut args = env::args().skip(1);
+	while let Some(arg) = args.next() {
+		let (key, value) = if arg == "-sock" {
+				(String::from("socket"), args.next())
+			} else if arg == "-dir" {
+				(String::from("dir"), args.next())
+			} else if let Some(flag) = arg.strip_prefix("--") {
+				match flag.find('=') {
+					Some(pos) => (flag[..pos].to_string(), Some(flag[pos + 1..].to_string())),
+					None => (flag.to_string(), args.next()),
+				}
+			} else {
+				return Err(format!("unexpected argument \"{}\"", arg));
+			};
+		let value = value.ok_or(format!("{}: missing value", arg))?;
+		if key == "config" {
+			path_config = Some(value);
+		} else {
+			overrides.push((key, value));
+		}
+	}
+	build(path_config.as_deref(), overrides)
+}
+
+// Reads the file at `path`, if any, and applies `overrides` on top, keyed as
+// the command line flags are. sessionctl uses it to find the daemon's files.
+pub fn build(path: Option<&str>, overrides: Vec<(String, String)>) -> Result<Config, String> {
+	let mut config = match path {
+		Some(path) => Config::from_file(path)?,
+		None => Config::default(),
+	};
+	for (key, value) in overrides {
+		config.set(key.as_str(), value)?;
+	}
+	config.validate()?;
+	Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn commands(list: &[&str]) -> Vec<String> {
+		list.iter().map(|c| c.to_string()).collect()
+	}
+
+	#[test]
+	fn acl_default_applies_to_unlisted_peers() {
+		let mut acl = Acl::default();
+		assert!(acl.allows(None, None, "DELETE"));
+		assert!(acl.allows(Some(1000), Some(1000), "DELETE"));
+		acl.default = commands(&["AUTH", "SESSION"]);
+		acl.uid.insert(String::from("0"), commands(&["*"]));
+		assert!(acl.allows(None, None, "AUTH"));
+		assert!(! acl.allows(None, None, "DELETE"));
+		assert!(acl.allows(Some(1000), Some(1000), "SESSION"));
+		assert!(! acl.allows(Some(1000), Some(1000), "DELETE"));
+		assert!(acl.allows(Some(0), Some(1000), "DELETE"));
+	}
+
+	#[test]
+	fn acl_unites_uid_and_gid_lists() {
+		let mut acl = Acl::default();
+		acl.uid.insert(String::from("1000"), commands(&["LOGIN"]));
+		acl.gid.insert(String::from("50"), commands(&["CREATE"]));
+		assert!(acl.allows(Some(1000), Some(50), "LOGIN"));
+		assert!(acl.allows(Some(1000), Some(50), "CREATE"));
+		assert!(acl.allows(Some(1000), Some(100), "LOGIN"));
+		assert!(! acl.allows(Some(1000), Some(100), "CREATE"));
+		assert!(acl.allows(Some(2000), Some(50), "CREATE"));
+		// A listed peer no longer gets acl.default.
+		assert!(! acl.allows(Some(2000), Some(50), "LOGIN"));
+		assert!(! acl.allows(Some(1000), None, "SAVE"));
+	}
+
+	#[test]
+	fn acl_matches_certificate_names() {
+		let mut acl = Acl { network: commands(&["AUTH"]), ..Default::default() };
+		acl.cert.insert(String::from("admin.example.com"), commands(&["*"]));
+		acl.cert.insert(String::from("web.example.com"), commands(&["LOGIN"]));
+		assert!(acl.allows_network(&commands(&["admin.example.com"]), "DELETE"));
+		assert!(acl.allows_network(&commands(&["www.example.com", "web.example.com"]), "LOGIN"));
+		assert!(! acl.allows_network(&commands(&["web.example.com"]), "AUTH"));
+		assert!(acl.allows_network(&commands(&["www.example.com"]), "AUTH"));
+		assert!(! acl.allows_network(&[], "LOGIN"));
+	}
+
+	#[test]
+	fn acl_network_default_excludes_administration() {
+		let acl = Acl::default();
+		for cmd in ["AUTH", "LOGIN", "SESSION", "LOGOUT", "GET", "SET"].iter() {
+			assert!(acl.allows_network(&[], cmd));
+		}
+		for cmd in ["CREATE", "UPDATE", "DELETE", "UNLOCK", "LIST", "REVOKE-ALL", "SAVE"].iter() {
+			assert!(! acl.allows_network(&[], cmd));
+			assert!(acl.allows(None, None, cmd));
+		}
+	}
+
+	fn policy(duration: i64, growth: i64, duration_max: i64) -> LockPolicy {
+		LockPolicy { count: 5, duration, growth, duration_max }
+	}
+
+	#[test]
+	fn lock_duration_without_growth() {
+		let policy = policy(60, 1, 0);
+		for lockouts in 0..4 {
+			assert_eq!(policy.duration(lockouts), 60);
+		}
+		assert_eq!(policy.duration(u64::MAX), 60);
+	}
+
+	#[test]
+	fn lock_duration_grows_up_to_the_cap() {
+		let policy = policy(60, 2, 300);
+		let durations: Vec<i64> = (1..6).map(|lockouts| policy.duration(lockouts)).collect();
+		assert_eq!(durations, vec![60, 120, 240, 300, 300]);
+		assert_eq!(policy.duration(u64::MAX), 300);
+		assert_eq!(LockPolicy { duration: 600, ..policy }.duration(1), 300);
+	}
+
+	#[test]
+	fn lock_duration_saturates() {
+		let policy = policy(60, i64::MAX, 0);
+		assert_eq!(policy.duration(1), 60);
+		assert_eq!(policy.duration(2), i64::MAX);
+		assert_eq!(policy.duration(u64::MAX), i64::MAX);
+		assert!(! policy.has_expired(1, 2, i64::MAX - 1));
+		assert!(! policy.has_expired(i64::MAX, 2, i64::MAX - 1));
+	}
+
+	#[test]
+	fn lock_expiry() {
+		let policy = policy(60, 2, 0);
+		assert!(! policy.has_expired(1000, 1, 1059));
+		assert!(policy.has_expired(1000, 1, 1060));
+		assert!(! policy.has_expired(1000, 2, 1119));
+		assert!(policy.has_expired(1000, 2, 1120));
+	}
+
+	#[test]
+	fn zero_lock_duration_never_expires() {
+		let policy = policy(0, 2, 300);
+		assert_eq!(policy.duration(u64::MAX), 0);
+		assert!(! policy.has_expired(0, 1, i64::MAX));
+		assert!(! policy.has_expired(1000, 3, 1000));
+	}
+}
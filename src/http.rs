@@ -0,0 +1,290 @@
+use std::io::prelude::*;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use config::Config;
+use server;
+use super::{auth, create_user, login, update_user, Error, Origin, Session, SessionManager};
+
+const BODY_MAX: u64 = 65536;
+
+// Fields missing from a request body are treated as empty, like missing
+// arguments on the line protocol.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct Body {
+	name: String,
+	password: String,
+	label: String,
+	value: String,
+}
+
+// A status code, the protocol's v2 error code and a message.
+type Failure = (u16, &'static str, String);
+
+fn failure(error: Error) -> Failure {
+	let status = match error {
+		Error::BadCredentials(_) => 401,
+		Error::Locked(_) => 403,
+		Error::NotFound(_) | Error::NoAttribute(_) => 404,
+		Error::Exists(_) => 409,
+		Error::Invalid(_) => 400,
+		Error::Limit(_) => 429,
+		Error::Internal(_) => 500,
+	};
+	(status, error.code(), error.message().to_string())
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+	let bytes = s.as_bytes();
+	let mut out = Vec::with_capacity(bytes.len());
+	let mut i = 0;
+	while i < bytes.len() {
+		if bytes[i] == b'%' {
+			let hex = s.get(i + 1..i + 3)?;
+			out.push(u8::from_str_radix(hex, 16).ok()?);
+			i += 3;
+		} else {
+			out.push(bytes[i]);
+			i += 1;
+		}
+	}
+	String::from_utf8(out).ok()
+}
+
+fn segments(url: &str) -> Result<Vec<String>, Failure> {
+	url.split('?').next().unwrap_or("")
+		.split('/')
+		.filter(|s| !s.is_empty())
+		.map(|s| percent_decode(s).ok_or((400, "SYNTAX", String::from("Malformed path."))))
+		.collect()
+}
+
+// Maps a request to the line protocol command it stands for, which is also
+// the name the ACL knows it by. The /session routes act on the session named
+// by the Authorization header.
+fn route(method: &Method, path: &[&str]) -> Option<&'static str> {
+	let cmd = match (method, path) {
+		(&Method::Post, ["auth"]) => "AUTH",
+		(&Method::Post, ["login"]) => "LOGIN",
+		(&Method::Get, ["session"]) => "SESSION",
+		(&Method::Get, ["session", "info"]) => "SESSIONINFO",
+		(&Method::Get, ["session", "attributes"]) => "KEYS",
+		(&Method::Get, ["session", "attributes", _]) => "GET",
+		(&Method::Put, ["session", "attributes", _]) => "SET",
+		(&Method::Delete, ["session", "attributes", _]) => "DEL",
+		(&Method::Delete, ["session"]) => "LOGOUT",
+		(&Method::Post, ["users"]) => "CREATE",
+		(&Method::Put, ["users", _]) => "UPDATE",
+		(&Method::Delete, ["users", _]) => "DELETE",
+		(&Method::Post, ["users", _, "unlock"]) => "UNLOCK",
+		(&Method::Get, ["users", _, "sessions"]) => "LIST",
+		(&Method::Delete, ["users", _, "sessions"]) => "REVOKE-ALL",
+		(&Method::Post, ["save"]) => "SAVE",
+		_ => return None,
+	};
+	Some(cmd)
+}
+
+// Session IDs, and the admin token, travel as "Authorization: Bearer ID"
+// rather than in the URL, which proxies and access logs record.
+fn bearer(request: &Request) -> String {
+	request.headers().iter()
+		.find(|h| h.field.equiv("Authorization"))
+		.and_then(|h| h.value.as_str().strip_prefix("Bearer "))
+		.map(|id| id.trim().to_string())
+		.unwrap_or_default()
+}
+
+// Compares in time that depends only on the lengths, so the token cannot be
+// guessed a byte at a time.
+fn same_secret(a: &str, b: &str) -> bool {
+	a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// HTTP clients have no identity of their own: a request may run what
+// acl.network allows, or acl.http_admin if it bears the admin token.
+fn allowed(config: &Config, bearer: &str, cmd: &str) -> bool {
+	if !config.http_admin_token.is_empty() && same_secret(bearer, config.http_admin_token.as_str()) {
+		config.acl.allows_http_admin(cmd)
+	} else {
+		config.acl.allows_network(&[], cmd)
+	}
+}
+
+fn session_json(session: &Session) -> Value {
+	json!({
+		"name": session.name,
+		"created": session.created,
+		"last_accessed": session.last_accessed,
+		"method": session.method,
+		"source": session.source,
+		"last_source": session.last_source,
+		"label": session.label,
+	})
+}
+
+fn read_json<T: DeserializeOwned + Default>(request: &mut Request) -> Result<T, Failure> {
+	let mut buf = Vec::new();
+	request.as_reader().take(BODY_MAX + 1).read_to_end(&mut buf)
+		.map_err(|_| (400, "SYNTAX", String::from("Request body unreadable.")))?;
+	if buf.len() as u64 > BODY_MAX {
+		return Err((413, "TOO_LARGE", String::from("Request body too large.")));
+	}
+	if buf.iter().all(|b| b.is_ascii_whitespace()) {
+		return Ok(T::default());
+	}
+	serde_json::from_slice(&buf).map_err(|_| (400, "SYNTAX", String::from("Malformed JSON.")))
+}
+
+fn dispatch(session_manager: &Mutex<SessionManager>, request: &mut Request) -> Result<(u16, Value), Failure> {
+	let path = segments(request.url())?;
+	let path: Vec<&str> = path.iter().map(String::as_str).collect();
+	let cmd = route(request.method(), &path).ok_or((404, "NOT_FOUND", String::from("Not found.")))?;
+	let mut body: Body = match *request.method() {
+		Method::Post | Method::Put => read_json(request)?,
+		_ => Body::default(),
+	};
+	// Browsers cannot be asked for a label, so their user agent stands in.
+	if body.label.is_empty() {
+		if let Some(agent) = request.headers().iter().find(|h| h.field.equiv("User-Agent")) {
+			body.label = agent.value.to_string();
+		}
+	}
+	let source = match request.remote_addr() {
+		Some(addr) => format!("http:{}", addr),
+		None => String::from("http"),
+	};
+	let origin = Origin { source: source.as_str(), label: body.label.as_str() };
+	let session_id = bearer(request);
+	let session_id = session_id.as_str();
+	let internal = |_| (500, "INTERNAL", String::from("Internal error."));
+	if ! allowed(&session_manager.lock().map_err(internal)?.config, session_id, cmd) {
+		return Err((403, "DENIED", String::from("Permission denied.")));
+	}
+	// As on the line protocol, the password commands take the lock themselves.
+	let result = match cmd {
+		"AUTH" => auth(session_manager, body.name.as_str(), body.password.as_str())
+			.map(|_| (204, Value::Null)),
+		"LOGIN" => login(session_manager, body.name.as_str(), body.password.as_str(), origin)
+			.map(|session_id| (200, json!({ "session_id": session_id }))),
+		"CREATE" => create_user(session_manager, body.name.as_str(), body.password.as_str(), origin)
+			.map(|session_id| (201, json!({ "session_id": session_id }))),
+		"UPDATE" => update_user(session_manager, path[1], body.password.as_str())
+			.map(|_| (204, Value::Null)),
+		_ => {
+			let mut session_manager = session_manager.lock().map_err(internal)?;
+			match cmd {
+				"SESSION" => session_manager.is_logged_in(session_id, source.as_str())
+					.map(|session| (200, json!({ "name": session.name }))),
+				"SESSIONINFO" => session_manager.session_info(session_id)
+					.map(|session| (200, session_json(session))),
+				"KEYS" => session_manager.attribute_keys(session_id, source.as_str())
+					.map(|keys| (200, json!({ "keys": keys }))),
+				"GET" => session_manager.get_attribute(session_id, source.as_str(), path[2])
+					.map(|val| (200, json!({ "value": val }))),
+				"SET" => session_manager.set_attribute(session_id, source.as_str(), path[2], body.value.as_str())
+					.map(|_| (204, Value::Null)),
+				"DEL" => session_manager.delete_attribute(session_id, source.as_str(), path[2])
+					.map(|_| (204, Value::Null)),
+				"LOGOUT" => session_manager.logout(session_id)
+					.map(|session| (200, json!({ "name": session.name }))),
+				"DELETE" => session_manager.delete_user(path[1])
+					.map(|_| (204, Value::Null)),
+				"UNLOCK" => session_manager.unlock_user(path[1])
+					.map(|_| (204, Value::Null)),
+				"LIST" => Ok((200, json!({ "sessions": session_manager.list_sessions(path[1]) }))),
+				"REVOKE-ALL" => Ok((200, json!({ "revoked": session_manager.revoke_sessions(path[1]) }))),
+				_ => return session_manager.save()
+					.map(|_| (204, Value::Null))
+					.map_err(|e| (500, "INTERNAL", e.to_string())),
+			}
+		},
+	};
+	result.map_err(failure)
+}
+
+fn respond(session_manager: &Mutex<SessionManager>, mut request: Request) {
+	let (status, body) = match dispatch(session_manager, &mut request) {
+		Ok(reply) => reply,
+		Err((status, code, error)) => (status, json!({ "error": error, "code": code })),
+	};
+	let _ = if body.is_null() {
+			request.respond(Response::empty(status))
+		} else {
+			let content_type = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+			request.respond(Response::from_string(body.to_string()).with_status_code(status).with_header(content_type))
+		};
+}
+
+pub fn bind(addr: &str) -> Result<Arc<Server>, String> {
+	Server::http(addr).map(Arc::new).map_err(|e| format!("{}: {}", addr, e))
+}
+
+pub fn serve(session_manager: Arc<Mutex<SessionManager>>, server: Arc<server::Server>, http: Arc<Server>) {
+	for request in http.incoming_requests() {
+		// The request head has already been read, so there is no pending read
+		// to interrupt; shutdown just waits for the response.
+		let guard = match server::Server::register(&server, Box::new(|| {})) {
+			Some(guard) => guard,
+			None => break,
+		};
+		let sm = session_manager.clone();
+		thread::spawn(move || {
+			respond(&sm, request);
+			drop(guard);
+		});
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn errors_map_to_status_codes() {
+		let cases = [
+			(Error::BadCredentials("Login failed."), 401, "BAD_CREDENTIALS"),
+			(Error::Locked("Account locked."), 403, "LOCKED"),
+			(Error::NotFound("Session not found."), 404, "NOT_FOUND"),
+			(Error::NoAttribute("Attribute not found."), 404, "NO_ATTRIBUTE"),
+			(Error::Exists("User already exists."), 409, "EXISTS"),
+			(Error::Invalid("Invalid user name."), 400, "INVALID"),
+			(Error::Limit("Session limit reached."), 429, "LIMIT"),
+			(Error::Internal("Save failed."), 500, "INTERNAL"),
+		];
+		for (error, status, code) in cases {
+			let message = error.message().to_string();
+			assert_eq!(failure(error), (status, code, message));
+		}
+	}
+
+	#[test]
+	fn admin_token_selects_the_admin_acl() {
+		let mut config = Config::default();
+		assert!(allowed(&config, "", "LOGIN"));
+		assert!(! allowed(&config, "", "CREATE"));
+		config.http_admin_token = String::from("0123456789abcdef");
+		config.acl.http_admin = vec![String::from("CREATE"), String::from("DELETE")];
+		assert!(allowed(&config, "0123456789abcdef", "CREATE"));
+		assert!(! allowed(&config, "0123456789abcdef", "SAVE"));
+		assert!(! allowed(&config, "0123456789abcdeF", "CREATE"));
+		assert!(! allowed(&config, "0123456789abcde", "CREATE"));
+		assert!(allowed(&config, "0123456789abcde", "LOGIN"));
+	}
+
+	#[test]
+	fn requests_map_to_commands() {
+		assert_eq!(route(&Method::Post, &["login"]), Some("LOGIN"));
+		assert_eq!(route(&Method::Get, &["session"]), Some("SESSION"));
+		assert_eq!(route(&Method::Delete, &["session"]), Some("LOGOUT"));
+		assert_eq!(route(&Method::Put, &["session", "attributes", "theme"]), Some("SET"));
+		assert_eq!(route(&Method::Delete, &["users", "bob", "sessions"]), Some("REVOKE-ALL"));
+		assert_eq!(route(&Method::Get, &["sessions", "0123"]), None);
+		assert_eq!(route(&Method::Get, &["login"]), None);
+	}
+}
@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error as IoError, ErrorKind};
+use std::io::prelude::*;
+use std::os::unix::fs::OpenOptionsExt;
+
+use cdb::{self, CDBError, Record};
+
+// An append-only log of cdbmake-style records. Every append is fsynced before
+// it returns, so callers may acknowledge a change as soon as append succeeds.
+pub struct Journal {
+	file: File,
+}
+
+// The whole records at the start of `data`, and how many bytes they take.
+fn parse(data: &[u8]) -> (Vec<Record>, usize) {
+	let mut records = Vec::new();
+	let mut rest: &[u8] = data;
+	let mut len = 0;
+	while !rest.is_empty() {
+		match cdb::read_record(&mut rest) {
+			Ok(Some(record)) => {
+				records.push(record);
+				len = data.len() - rest.len();
+			},
+			_ => break,
+		}
+	}
+	(records, len)
+}
+
+// Reads the journal without opening it for appends or cutting off a torn
+// record, which it reports instead. A missing journal holds no records.
+pub fn read(path: &str) -> Result<(Vec<Record>, bool), IoError> {
+	let data = match fs::read(path) {
+		Ok(data) => data,
+		Err(ref e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+		Err(e) => return Err(e),
+	};
+	let (records, len) = parse(&data);
+	Ok((records, len < data.len()))
+}
+
+// User records the journal holds on top of users.cdb, as sessiond replays
+// them: those of users created since the last save, and those of users
+// users.cdb already holds.
+pub struct Pending {
+	pub created: HashMap<String, String>,
+	pub updated: HashMap<String, String>,
+}
+
+// Replays journal entries; `stored` tells whether users.cdb holds a name.
+pub fn replay<F: Fn(&str) -> bool>(records: Vec<Record>, stored: F) -> Pending {
+	let mut pending = Pending {
+		created: HashMap::new(),
+		updated: HashMap::new(),
+	};
+	for (key, val) in records {
+		let name = match String::from_utf8(key) {
+			Ok(name) => name,
+			Err(_) => continue,
+		};
+		let val = String::from_utf8_lossy(&val);
+		if let Some(rest) = val.strip_prefix("C ") {
+			// A crash after save installed users.cdb but before it cleared the
+			// journal leaves creations that users.cdb already holds.
+			if stored(name.as_str()) {
+				pending.updated.insert(name, rest.to_string());
+			} else {
+				pending.created.insert(name, rest.to_string());
+			}
+		} else if let Some(rest) = val.strip_prefix("U ") {
+			pending.updated.insert(name, rest.to_string());
+		} else if val == "D" {
+			pending.created.remove(&name);
+		}
+	}
+	pending
+}
+
+impl Journal {
+	// Opens (creating if needed) the journal and returns the records it holds.
+	// A torn record left by a crash mid-append is cut off.
+	pub fn open(path: &str) -> Result<(Journal, Vec<Record>), CDBError> {
+		let mut file = OpenOptions::new().read(true).append(true).create(true).mode(0o660).open(path)?;
+		let mut data = Vec::new();
+		file.read_to_end(&mut data)?;
+		let (records, len) = parse(&data);
+		if len < data.len() {
+			file.set_len(len as u64)?;
+			file.sync_all()?;
+		}
+		Ok((Journal { file }, records))
+	}
+	pub fn append(&mut self, key: &[u8], val: &[u8]) -> Result<(), IoError> {
+		let mut buf = Vec::new();
+		cdb::write_record(&mut buf, key, val)?;
+		self.write_with(|file| file.write_all(&buf).and_then(|_| file.sync_data()))
+	}
+	// Runs `write` at the end of the file and cuts the file back to where it
+	// was if it fails, so that a torn record never has later ones after it,
+	// where open would drop them with it.
+	fn write_with<F: FnOnce(&mut File) -> Result<(), IoError>>(&mut self, write: F) -> Result<(), IoError> {
+		let len = self.file.metadata()?.len();
+		let result = write(&mut self.file);
+		if result.is_err() {
+			let _ = self.file.set_len(len).and_then(|_| self.file.sync_all());
+		}
+		result
+	}
+	pub fn clear(&mut self) -> Result<(), IoError> {
+		self.file.set_len(0)?;
+		self.file.sync_all()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::env;
+	use std::fs;
+	use std::process;
+
+	fn records(entries: &[(&str, &str)]) -> Vec<Record> {
+		entries.iter().map(|&(key, val)| (key.as_bytes().to_vec(), val.as_bytes().to_vec())).collect()
+	}
+
+	#[test]
+	fn deletions_only_drop_pending_creations() {
+		let pending = replay(records(&[
+			("bob", "C one"),
+			("carol", "C two"),
+			("carol", "D"),
+			("dave", "U three"),
+			("dave", "D"),
+		]), |name| name == "bob" || name == "dave");
+		assert!(pending.created.is_empty());
+		assert_eq!(pending.updated.len(), 2);
+		assert_eq!(pending.updated["bob"], "one");
+		assert_eq!(pending.updated["dave"], "three");
+	}
+
+	#[test]
+	fn failed_append_leaves_no_torn_record() {
+		let mut path = env::temp_dir();
+		path.push(format!("sessiond-journal-{}-torn", process::id()));
+		let path = path.to_str().unwrap().to_string();
+		let _ = fs::remove_file(path.as_str());
+		let (mut journal, _) = Journal::open(path.as_str()).unwrap();
+		journal.append(b"alice", b"C one").unwrap();
+		// As a write cut short by a full disk.
+		let result = journal.write_with(|file| {
+			file.write_all(b"+3,5:bob->U")?;
+			Err(IoError::other("No space left on device"))
+		});
+		assert!(result.is_err());
+		journal.append(b"carol", b"C two").unwrap();
+		drop(journal);
+		let (_, records) = Journal::open(path.as_str()).unwrap();
+		assert_eq!(records, vec![
+			(b"alice".to_vec(), b"C one".to_vec()),
+			(b"carol".to_vec(), b"C two".to_vec()),
+		]);
+		let _ = fs::remove_file(path.as_str());
+	}
+}
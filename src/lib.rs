@@ -0,0 +1,11 @@
+// The on-disk formats and configuration that sessiond and sessionctl share.
+extern crate memmap2;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate toml;
+extern crate unicode_normalization;
+
+pub mod cdb;
+pub mod config;
+pub mod journal;
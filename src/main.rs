@@ -1,63 +1,158 @@
 extern crate time;
 extern crate rand;
+extern crate argon2;
 extern crate libc;
+extern crate rustls;
+extern crate rustls_pemfile;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate sessiond;
+extern crate sessiond_client;
+extern crate signal_hook;
+extern crate tiny_http;
+extern crate webpki;
 
-mod cdb;
+mod http;
+mod password;
+mod server;
+mod tls;
 
-use std::char;
-use std::collections::HashMap;
-use std::env;
-use std::error::Error;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
 use std::fs::File;
 use std::fs;
 use std::io::prelude::*;
-use std::io::{BufReader, BufWriter, Error as IoError};
+use std::io::{self, BufReader, BufWriter, Error as IoError, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::fs::FileTypeExt;
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "dragonfly", target_os = "openbsd", target_os = "netbsd"))]
+use std::os::unix::io::AsRawFd;
 use std::os::unix::net::{UnixListener, UnixStream};
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::process;
+use std::str;
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread;
 use std::time::Duration;
 
-use rand::Rng;
+use rand::{OsRng, Rng};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
 
-const LOCK_COUNT: u64 = 5;
-const SESSION_PERIOD: i64 = 3600;
-const FILE_SOCKET: &'static str = "sessiond.sock";
-const FILE_USERS_CDB: &'static str = "users.cdb";
-const FILE_USERS_OLD: &'static str = "users.old";
-const FILE_USERS_NEW: &'static str = "users.new";
-const FILE_USERS_TMP: &'static str = "users.tmp";
+use sessiond::{cdb, config, journal};
+use sessiond::config::dir_file;
+use sessiond_client::args;
+
+const PROTOCOL_VERSION: u32 = 2;
+const SESSION_ID_BYTES: usize = 32;
+const FILE_SESSIONS_CDB: &str = "sessions.cdb";
+const FILE_SESSIONS_TMP: &str = "sessions.tmp";
+
+// Why a command failed: the v1 reply text, which old clients match on, and the
+// kind of failure, which v2 replies report as a code.
+#[derive(Clone, Copy, Debug)]
+enum Error {
+	BadCredentials(&'static str),
+	Locked(&'static str),
+	NotFound(&'static str),
+	NoAttribute(&'static str),
+	Exists(&'static str),
+	Invalid(&'static str),
+	Limit(&'static str),
+	Internal(&'static str),
+}
+
+impl Error {
+	fn code(&self) -> &'static str {
+		match *self {
+			Error::BadCredentials(_) => "BAD_CREDENTIALS",
+			Error::Locked(_) => "LOCKED",
+			Error::NotFound(_) => "NOT_FOUND",
+			Error::NoAttribute(_) => "NO_ATTRIBUTE",
+			Error::Exists(_) => "EXISTS",
+			Error::Invalid(_) => "INVALID",
+			Error::Limit(_) => "LIMIT",
+			Error::Internal(_) => "INTERNAL",
+		}
+	}
+	fn message(&self) -> &'static str {
+		match *self {
+			Error::BadCredentials(m) | Error::Locked(m) | Error::NotFound(m) | Error::NoAttribute(m) | Error::Exists(m) | Error::Invalid(m) | Error::Limit(m) | Error::Internal(m) => m,
+		}
+	}
+}
+
+impl From<&'static str> for Error {
+	fn from(m: &'static str) -> Error {
+		Error::Internal(m)
+	}
+}
 
 enum SaveError {
 	Msg(&'static str),
 	Io(IoError),
 }
 
+impl fmt::Display for SaveError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			SaveError::Msg(m) => write!(f, "{}", m),
+			SaveError::Io(ref e) => write!(f, "{}", e),
+		}
+	}
+}
+
 impl From<IoError> for SaveError {
 	fn from(e: IoError) -> SaveError {
 		SaveError::Io(e)
 	}
 }
 
+impl From<cdb::CDBError> for SaveError {
+	fn from(e: cdb::CDBError) -> SaveError {
+		match e {
+			cdb::CDBError::Msg(m) => SaveError::Msg(m),
+			cdb::CDBError::Io(e) => SaveError::Io(e),
+		}
+	}
+}
+
 fn bytes_to_string(bytes: &[u8]) -> String {
 	let mut ret = String::new();
 	for b in bytes.iter() {
 		let hi: u8 = *b >> 4 & 15;
 		if hi <= 9 {
 			ret.push((b'0' + hi) as char);
-		} else if 10 <= hi && hi <= 15 {
+		} else if (10..=15).contains(&hi) {
 			ret.push((b'A' + hi - 10) as char);
 		}
 		let lo: u8 = *b & 15;
 		if lo <= 9 {
 			ret.push((b'0' + lo) as char);
-		} else if 10 <= lo && lo <= 15 {
+		} else if (10..=15).contains(&lo) {
 			ret.push((b'A' + lo - 10) as char);
 		}
 	}
 	ret
 }
 
+// RFC 4648 base64url without padding.
+fn bytes_to_base64url(bytes: &[u8]) -> String {
+	const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+	let mut ret = String::new();
+	for chunk in bytes.chunks(3) {
+		let n = chunk.iter().enumerate().fold(0u32, |n, (i, b)| n | u32::from(*b) << (16 - 8 * i));
+		for i in 0..=chunk.len() {
+			ret.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+		}
+	}
+	ret
+}
+
+#[derive(Clone)]
 struct User {
 	name: String,
 	password: String,
@@ -68,13 +163,14 @@ struct User {
 	failed: i64,
 	fail_count: u64,
 	locked: i64,
+	lockouts: u64,
 }
 
 impl User {
-	fn new(name: &str, password: &str) -> User {
+	fn new(name: &str, password: String) -> User {
 		User {
 			name: name.to_string(),
-			password: password.to_string(),
+			password,
 			created: time::get_time().sec,
 			updated: 0,
 			deleted: 0,
@@ -82,42 +178,70 @@ impl User {
 			failed: 0,
 			fail_count: 0,
 			locked: 0,
+			lockouts: 0,
 		}
 	}
 	fn parse(name: &str, rest: &str) -> User {
 		let mut parts = rest.split_whitespace();
 		User {
 			name: name.to_string(),
-			password: parts.next().map_or(String::new(), |s| String::from(s)),
-			created: parts.next().map_or(0, |s| i64::from_str_radix(s, 10).unwrap_or(0)),
-			updated: parts.next().map_or(0, |s| i64::from_str_radix(s, 10).unwrap_or(0)),
-			deleted: parts.next().map_or(0, |s| i64::from_str_radix(s, 10).unwrap_or(0)),
-			last_loggedin: parts.next().map_or(0, |s| i64::from_str_radix(s, 10).unwrap_or(0)),
-			failed: parts.next().map_or(0, |s| i64::from_str_radix(s, 10).unwrap_or(0)),
-			fail_count: parts.next().map_or(0, |s| u64::from_str_radix(s, 10).unwrap_or(0)),
-			locked: parts.next().map_or(0, |s| i64::from_str_radix(s, 10).unwrap_or(0)),
-		}
-	}
-	fn to_string(&self) -> String {
-		let mut buf = String::new();
-		buf.push_str(self.name.as_str());
-		buf.push('\x20');
-		buf.push_str(self.password.as_str());
-		buf.push('\x20');
-		buf.push_str(self.created.to_string().as_str());
-		buf.push('\x20');
-		buf.push_str(self.updated.to_string().as_str());
-		buf.push('\x20');
-		buf.push_str(self.deleted.to_string().as_str());
-		buf.push('\x20');
-		buf.push_str(self.last_loggedin.to_string().as_str());
-		buf.push('\x20');
-		buf.push_str(self.failed.to_string().as_str());
-		buf.push('\x20');
-		buf.push_str(self.fail_count.to_string().as_str());
-		buf.push('\x20');
-		buf.push_str(self.locked.to_string().as_str());
-		buf
+			password: parts.next().map_or(String::new(), String::from),
+			created: parts.next().map_or(0, |s| s.parse::<i64>().unwrap_or(0)),
+			updated: parts.next().map_or(0, |s| s.parse::<i64>().unwrap_or(0)),
+			deleted: parts.next().map_or(0, |s| s.parse::<i64>().unwrap_or(0)),
+			last_loggedin: parts.next().map_or(0, |s| s.parse::<i64>().unwrap_or(0)),
+			failed: parts.next().map_or(0, |s| s.parse::<i64>().unwrap_or(0)),
+			fail_count: parts.next().map_or(0, |s| s.parse::<u64>().unwrap_or(0)),
+			locked: parts.next().map_or(0, |s| s.parse::<i64>().unwrap_or(0)),
+			lockouts: parts.next().map_or(0, |s| s.parse::<u64>().unwrap_or(0)),
+		}
+	}
+	fn record(&self) -> String {
+		format!("{} {} {} {} {} {} {} {} {}",
+			self.password,
+			self.created,
+			self.updated,
+			self.deleted,
+			self.last_loggedin,
+			self.failed,
+			self.fail_count,
+			self.locked,
+			self.lockouts)
+	}
+	// Lifts a lockout that has run its course and turns away deleted or locked
+	// users. `failed` is the message the caller reports failures with.
+	fn check_lock(&mut self, policy: config::LockPolicy, failed: &'static str) -> Result<(), Error> {
+		if self.is_locked() && policy.has_expired(self.locked, self.lockouts, time::get_time().sec) {
+			self.locked = 0;
+			self.fail_count = 0;
+		}
+		if self.is_deleted() {
+			return Err(Error::BadCredentials(failed));
+		}
+		if self.is_locked() {
+			return Err(Error::Locked(failed));
+		}
+		Ok(())
+	}
+	fn record_attempt(&mut self, verified: bool, policy: config::LockPolicy, failed: &'static str) -> Result<(), Error> {
+		if verified {
+			self.fail_count = 0;
+			self.lockouts = 0;
+			Ok(())
+		} else {
+			self.failed = time::get_time().sec;
+			self.fail_count += 1;
+			if self.fail_count >= policy.count {
+				self.locked = self.failed;
+				self.lockouts += 1;
+			}
+			Err(Error::BadCredentials(failed))
+		}
+	}
+	fn unlock(&mut self) {
+		self.locked = 0;
+		self.fail_count = 0;
+		self.lockouts = 0;
 	}
 	fn is_deleted(&self) -> bool {
 		self.deleted != 0
@@ -127,462 +251,1004 @@ impl User {
 	}
 }
 
+// Where a request came from, and the label the client gave, if any.
+#[derive(Clone, Copy)]
+struct Origin<'a> {
+	source: &'a str,
+	label: &'a str,
+}
+
 struct Session {
 	name: String,
+	created: i64,
 	last_accessed: i64,
+	// How the session was opened: "password" for LOGIN, "create" for CREATE.
+	method: String,
+	// The peer that opened the session and the one that last used it, such as
+	// "unix:uid=1000,pid=4242" or "tcp:192.0.2.1:50000".
+	source: String,
+	last_source: String,
+	label: String,
+	// Small values the application keeps with the session; they go when it does.
+	attributes: BTreeMap<String, String>,
+	// Orders sessions created within the same second; not persisted.
+	serial: u64,
 }
 
 impl Session {
-	fn new(name: &str) -> Session {
+	fn new(name: &str, method: &str, origin: Origin) -> Session {
+		let now = time::get_time().sec;
 		Session {
 			name: name.to_string(),
-			last_accessed: time::get_time().sec,
+			created: now,
+			last_accessed: now,
+			method: method.to_string(),
+			source: origin.source.to_string(),
+			last_source: origin.source.to_string(),
+			label: origin.label.to_string(),
+			attributes: BTreeMap::new(),
+			serial: 0,
+		}
+	}
+	// Records are "name last_accessed [created [method source last_source
+	// label [key value]...]]" in the protocol's quoting; older snapshots lack
+	// the later fields.
+	fn parse(rest: &str) -> Option<Session> {
+		let mut parts = args::split(rest).ok()?.into_iter();
+		let name = parts.next()?;
+		let last_accessed = parts.next()?.parse::<i64>().ok()?;
+		let created = parts.next().map_or(last_accessed, |s| s.parse::<i64>().unwrap_or(last_accessed));
+		let mut field = || parts.next().unwrap_or_default();
+		let (method, source, last_source, label) = (field(), field(), field(), field());
+		let mut attributes = BTreeMap::new();
+		while let (Some(key), Some(val)) = (parts.next(), parts.next()) {
+			attributes.insert(key, val);
+		}
+		Some(Session {
+			name,
+			created,
+			last_accessed,
+			method,
+			source,
+			last_source,
+			label,
+			attributes,
+			serial: 0,
+		})
+	}
+	fn record(&self) -> String {
+		let mut fields = vec![
+			self.name.clone(),
+			self.last_accessed.to_string(),
+			self.created.to_string(),
+			self.method.clone(),
+			self.source.clone(),
+			self.last_source.clone(),
+			self.label.clone(),
+		];
+		for (key, val) in self.attributes.iter() {
+			fields.push(key.clone());
+			fields.push(val.clone());
 		}
+		fields.iter().map(|field| args::quote(field)).collect::<Vec<String>>().join(" ")
+	}
+	fn attributes_size(&self) -> usize {
+		self.attributes.iter().map(|(key, val)| key.len() + val.len()).sum()
+	}
+	// The reply to SESSIONINFO: field names alternating with their values.
+	fn info(&self) -> String {
+		let fields = [
+			("name", self.name.clone()),
+			("created", self.created.to_string()),
+			("last_accessed", self.last_accessed.to_string()),
+			("method", self.method.clone()),
+			("source", self.source.clone()),
+			("last_source", self.last_source.clone()),
+			("label", self.label.clone()),
+		];
+		fields.iter().map(|&(key, ref val)| format!("{} {}", key, args::quote(val))).collect::<Vec<String>>().join(" ")
 	}
-	fn update(&mut self) {
+	fn update(&mut self, source: &str) {
 		self.last_accessed = time::get_time().sec;
+		if self.last_source != source {
+			self.last_source = source.to_string();
+		}
+	}
+	fn is_expired(&self, now: i64, config: &config::Config) -> bool {
+		self.last_accessed + config.session_period <= now ||
+		(config.session_lifetime > 0 && self.created + config.session_lifetime <= now)
+	}
+}
+
+// A password check done in three steps so that Argon2 never runs under the
+// SessionManager lock: begin_check copies the stored password out, verify runs
+// unlocked, and finish_check applies the outcome.
+struct PasswordCheck {
+	name: String,
+	stored: String,
+	// False for a missing or deleted user, whose check runs against
+	// password::DUMMY and always fails.
+	known: bool,
+	verified: bool,
+	// The password hashed, when it matched a legacy plaintext one.
+	upgrade: Option<String>,
+}
+
+impl PasswordCheck {
+	fn verify(&mut self, pass: &str) {
+		self.verified = password::verify(self.stored.as_str(), pass) && self.known;
+		if self.verified && ! password::is_hashed(self.stored.as_str()) {
+			self.upgrade = password::hash(pass).ok();
+		}
 	}
 }
 
 struct SessionManager {
-	seqno: u8,
-	dir: String,
+	config: config::Config,
 	path_users_cdb: String,
+	users: Option<cdb::Cdb>,
 	sessions: HashMap<String, Session>,
+	// Session IDs by user name, kept in step with `sessions`.
+	user_sessions: HashMap<String, HashSet<String>>,
+	next_serial: u64,
 	created_users: HashMap<String, User>,
 	updated_users: HashMap<String, User>,
+	// Stored user names by their normalized form, so that a name stored before
+	// names were normalized is reached, rather than shadowed, by every
+	// spelling that normalizes like it.
+	names: HashMap<String, String>,
+	journal: journal::Journal,
 }
 
 impl SessionManager {
-	fn new(dir: String) -> SessionManager {
-		let path = if dir.len() != 0 {
-				let mut path_buf = PathBuf::from(dir.clone());
-				path_buf.push(FILE_USERS_CDB);
-				path_buf.as_path().to_str().unwrap_or(FILE_USERS_CDB).to_string()
-			} else {
-				String::from(FILE_USERS_CDB)
-			};
-		SessionManager {
-			seqno: 0,
-			dir: dir,
+	fn new(config: config::Config) -> Result<SessionManager, String> {
+		let dir = config.dir.as_str();
+		let path = dir_file(dir, config.users_cdb.as_str());
+		let users = cdb::Cdb::open_if_exists(path.as_str()).map_err(|e| format!("{}: {}", path, e))?;
+		let path_sessions = dir_file(dir, FILE_SESSIONS_CDB);
+		let sessions = load_sessions(path_sessions.as_str(), &config).map_err(|e| format!("{}: {}", path_sessions, e))?;
+		let path_journal = dir_file(dir, config.users_journal.as_str());
+		let (journal, records) = journal::Journal::open(path_journal.as_str()).map_err(|e| format!("{}: {}", path_journal, e))?;
+		let mut user_sessions: HashMap<String, HashSet<String>> = HashMap::new();
+		for (session_id, session) in sessions.iter() {
+			user_sessions.entry(session.name.clone()).or_default().insert(session_id.clone());
+		}
+		let mut session_manager = SessionManager {
+			config,
 			path_users_cdb: path,
-			sessions: HashMap::new(),
+			users,
+			sessions,
+			user_sessions,
+			next_serial: 1,
 			created_users: HashMap::new(),
 			updated_users: HashMap::new(),
+			names: HashMap::new(),
+			journal,
+		};
+		session_manager.replay(records);
+		session_manager.index_names();
+		Ok(session_manager)
+	}
+	fn reconfigure(&mut self, config: config::Config) {
+		if ! self.config.reload(config) {
+			eprintln!("sessiond: socket, dir and users file changes take effect after a restart");
+		}
+		// The username policy may have changed.
+		self.index_names();
+	}
+	// Indexes users.cdb and the pending users by normalized name. Where two
+	// stored names normalize alike, the one already normalized wins.
+	fn index_names(&mut self) {
+		let policy = self.config.username_policy();
+		let stored = self.users.as_ref().and_then(|cdb| cdb.iter().ok()).into_iter().flatten()
+			.filter_map(|record| record.ok())
+			.filter_map(|(key, _)| str::from_utf8(key).ok().map(String::from));
+		let mut names = HashMap::new();
+		for name in stored.chain(self.created_users.keys().cloned()).chain(self.updated_users.keys().cloned()) {
+			let normalized = policy.normalize(name.as_str());
+			if normalized == name {
+				names.insert(normalized, name);
+			} else {
+				names.entry(normalized).or_insert(name);
+			}
+		}
+		self.names = names;
+	}
+	fn lookup_user(&self, name: &str) -> Option<User> {
+		let cdb = self.users.as_ref()?;
+		match cdb.get(name.as_bytes()) {
+			Ok(Some(val)) => str::from_utf8(val).ok().map(|s| User::parse(name, s)),
+			_ => None,
 		}
 	}
 	fn clean(&mut self) {
 		let now = time::get_time().sec;
-		let keys: Vec<String> = self.sessions.iter().filter(|&(_, v)| v.last_accessed + SESSION_PERIOD < now).map(|(k, _)| k.clone()).collect();
+		let keys: Vec<String> = self.sessions.iter().filter(|&(_, v)| v.is_expired(now, &self.config)).map(|(k, _)| k.clone()).collect();
 		for session_id in keys {
-			self.sessions.remove(&session_id);
-		}
-	}
-	fn create_session_id(&mut self) -> String {
-		let mut bytes: [u8; 16] = [0; 16];
-		let mut rng = rand::thread_rng();
-		rng.fill_bytes(&mut bytes[..15]);
-		self.seqno = if self.seqno == u8::max_value() { 0 } else { self.seqno + 1 };
-		bytes[15] = self.seqno;
-		bytes_to_string(&bytes)
-	}
-	fn auth(&mut self, name: &str, pass: &str) -> Result<(), &'static str> {
-		let mut result = false;
-		if self.created_users.contains_key(name) {
-			if let Some(user) = self.created_users.get_mut(name) {
-				if ! user.is_locked() {
-					if user.password == pass {
-						user.fail_count = 0;
-						result = true;
-					} else {
-						user.failed = time::get_time().sec;
-						user.fail_count += 1;
-						if user.fail_count >= LOCK_COUNT {
-							user.locked = user.failed;
-						}
-					}
-				}
-			}
-		} else if self.updated_users.contains_key(name) {
-			if let Some(user) = self.updated_users.get_mut(name) {
-				if ! user.is_locked() && ! user.is_deleted() {
-					if user.password == pass {
-						user.fail_count = 0;
-						result = true;
-					} else {
-						user.failed = time::get_time().sec;
-						user.fail_count += 1;
-						if user.fail_count >= LOCK_COUNT {
-							user.locked = user.failed;
-						}
-					}
-				}
+			self.remove_session(&session_id);
+		}
+	}
+	fn insert_session(&mut self, session_id: String, mut session: Session) {
+		session.serial = self.next_serial;
+		self.next_serial += 1;
+		self.user_sessions.entry(session.name.clone()).or_default().insert(session_id.clone());
+		self.sessions.insert(session_id, session);
+	}
+	fn remove_session(&mut self, session_id: &str) -> Option<Session> {
+		let session = self.sessions.remove(session_id)?;
+		if let Some(ids) = self.user_sessions.get_mut(&session.name) {
+			ids.remove(session_id);
+			if ids.is_empty() {
+				self.user_sessions.remove(&session.name);
 			}
-		} else if let Ok(s) = cdb::cdb_get(self.path_users_cdb.as_str(), name) {
-			let mut user = User::parse(name, s.as_str());
-			if ! user.is_locked() && ! user.is_deleted() {
-				if user.password == pass {
-					user.fail_count = 0;
-					result = true;
-				} else {
-					user.failed = time::get_time().sec;
-					user.fail_count += 1;
-					if user.fail_count >= LOCK_COUNT {
-						user.locked = user.failed;
-					}
-				}
+		}
+		Some(session)
+	}
+	fn create_session_id(&self) -> Result<String, &'static str> {
+		let mut rng = OsRng::new().map_err(|_| "Session ID generation failed.")?;
+		loop {
+			let mut bytes: [u8; SESSION_ID_BYTES] = [0; SESSION_ID_BYTES];
+			rng.fill_bytes(&mut bytes);
+			let session_id = match self.config.session_id_encoding {
+				config::SessionIdEncoding::Hex => bytes_to_string(&bytes),
+				config::SessionIdEncoding::Base64url => bytes_to_base64url(&bytes),
+			};
+			if ! self.sessions.contains_key(&session_id) {
+				return Ok(session_id);
 			}
+		}
+	}
+	// Returns the pending copy of a user, pulling it from users.cdb into
+	// updated_users on first touch.
+	fn pending_user(&mut self, name: &str) -> Option<&mut User> {
+		if ! self.created_users.contains_key(name) && ! self.updated_users.contains_key(name) {
+			let user = self.lookup_user(name)?;
 			self.updated_users.insert(name.to_string(), user);
 		}
-		if result {
-			Ok(())
-		} else {
-			Err("Authentication failed.")
+		if let Some(user) = self.created_users.get_mut(name) {
+			return Some(user);
 		}
+		self.updated_users.get_mut(name)
 	}
-	fn login(&mut self, name: &str, pass: &str) -> Result<String, &'static str> {
-		let mut result = false;
-		if self.created_users.contains_key(name) {
-			if let Some(user) = self.created_users.get_mut(name) {
-				if ! user.is_locked() {
-					if user.password == pass {
-						user.fail_count = 0;
-						user.last_loggedin = time::get_time().sec;
-						result = true;
-					} else {
-						user.failed = time::get_time().sec;
-						user.fail_count += 1;
-						if user.fail_count >= LOCK_COUNT {
-							user.locked = user.failed;
-						}
-					}
-				}
+	// The user's pending copies, taken before a change so that journal_change
+	// can put them back.
+	fn pending_copies(&self, name: &str) -> (Option<User>, Option<User>) {
+		(self.created_users.get(name).cloned(), self.updated_users.get(name).cloned())
+	}
+	// Journals a change already made to the user, undoing it if the journal
+	// cannot be written so that memory never runs ahead of the disk.
+	fn journal_change(&mut self, name: &str, before: (Option<User>, Option<User>)) -> Result<(), Error> {
+		let result = self.journal(name);
+		if result.is_err() {
+			let (created, updated) = before;
+			for (users, user) in [(&mut self.created_users, created), (&mut self.updated_users, updated)] {
+				match user {
+					Some(user) => users.insert(name.to_string(), user),
+					None => users.remove(name),
+				};
 			}
-		} else if self.updated_users.contains_key(name) {
-			if let Some(user) = self.updated_users.get_mut(name) {
-				if ! user.is_locked() && ! user.is_deleted() {
-					if user.password == pass {
-						user.fail_count = 0;
-						user.last_loggedin = time::get_time().sec;
-						result = true;
-					} else {
-						user.failed = time::get_time().sec;
-						user.fail_count += 1;
-						if user.fail_count >= LOCK_COUNT {
-							user.locked = user.failed;
-						}
-					}
-				}
+		}
+		result
+	}
+	fn journal(&mut self, name: &str) -> Result<(), Error> {
+		let val = if let Some(user) = self.created_users.get(name) {
+				format!("C {}", user.record())
+			} else if let Some(user) = self.updated_users.get(name) {
+				format!("U {}", user.record())
+			} else {
+				String::from("D")
+			};
+		self.journal.append(name.as_bytes(), val.as_bytes()).map_err(|_| Error::Internal("Journal write failed."))
+	}
+	fn replay(&mut self, records: Vec<cdb::Record>) {
+		let pending = journal::replay(records, |name| self.lookup_user(name).is_some());
+		for (users, records) in [(&mut self.created_users, pending.created), (&mut self.updated_users, pending.updated)] {
+			for (name, record) in records {
+				let user = User::parse(name.as_str(), record.as_str());
+				users.insert(name, user);
 			}
-		} else if let Ok(s) = cdb::cdb_get(self.path_users_cdb.as_str(), name) {
-			let mut user = User::parse(name, s.as_str());
-			if ! user.is_locked() && ! user.is_deleted() {
-				if user.password == pass {
-					user.fail_count = 0;
-					user.last_loggedin = time::get_time().sec;
-					result = true;
-				} else {
-					user.failed = time::get_time().sec;
-					user.fail_count += 1;
-					if user.fail_count >= LOCK_COUNT {
-						user.locked = user.failed;
+		}
+	}
+	// Users are looked up by their normalized name, which leads to the name
+	// they are stored under. A name no user has is returned normalized.
+	fn username(&self, name: &str) -> String {
+		let normalized = self.config.username_policy().normalize(name);
+		self.names.get(&normalized).cloned().unwrap_or(normalized)
+	}
+	fn begin_check(&mut self, name: &str, failed: &'static str) -> Result<PasswordCheck, Error> {
+		let name = self.username(name);
+		let policy = self.config.lock_policy();
+		let stored = match self.pending_user(name.as_str()) {
+			Some(user) if ! user.is_deleted() => {
+				user.check_lock(policy, failed)?;
+				Some(user.password.clone())
+			},
+			_ => None,
+		};
+		Ok(PasswordCheck {
+			known: stored.is_some(),
+			stored: stored.unwrap_or_else(|| password::DUMMY.to_string()),
+			name,
+			verified: false,
+			upgrade: None,
+		})
+	}
+	// A successful `login` also stamps last_loggedin, unless the session limit
+	// turns it away, which leaves the user as it was.
+	fn finish_check(&mut self, check: PasswordCheck, login: bool, failed: &'static str) -> Result<(), Error> {
+		if ! check.known {
+			return Err(Error::BadCredentials(failed));
+		}
+		let policy = self.config.lock_policy();
+		let name = check.name.as_str();
+		let room = if login && check.verified { self.check_room(name) } else { Ok(()) };
+		let before = self.pending_copies(name);
+		let result = match self.pending_user(name) {
+			// The password was changed or the user deleted while it was verified.
+			// A legacy password that another check has since hashed is unchanged
+			// if the hash still matches it, which only that rare race pays for
+			// with an Argon2 verify under the lock.
+			Some(ref user) if user.is_deleted() || (user.password != check.stored && ! (
+				check.upgrade.is_some() && password::verify(user.password.as_str(), check.stored.as_str())
+			)) => return Err(Error::BadCredentials(failed)),
+			Some(user) => {
+				let result = user.check_lock(policy, failed)
+					.and(room)
+					.and_then(|_| user.record_attempt(check.verified, policy, failed));
+				if result.is_ok() {
+					match check.upgrade {
+						Some(hashed) if user.password == check.stored => user.password = hashed,
+						_ => {},
+					}
+					if login {
+						user.last_loggedin = time::get_time().sec;
 					}
 				}
-			}
-			self.updated_users.insert(name.to_string(), user);
+				result
+			},
+			None => return Err(Error::BadCredentials(failed)),
+		};
+		self.journal_change(name, before)?;
+		result
+	}
+	fn open_session(&mut self, name: &str, method: &str, origin: Origin) -> Result<String, Error> {
+		let session_id = self.create_session_id()?;
+		self.insert_session(session_id.clone(), Session::new(name, method, origin));
+		Ok(session_id)
+	}
+	// Fails if the user holds as many sessions as allowed and the policy is to
+	// turn new ones away.
+	fn check_room(&self, name: &str) -> Result<(), Error> {
+		let limit = self.config.session_limit;
+		match self.config.session_limit_policy {
+			config::SessionLimitPolicy::Reject if limit > 0 && self.list_sessions(name).len() >= limit => Err(Error::Limit("Session limit reached.")),
+			_ => Ok(()),
 		}
-		if result {
-			let session_id = self.create_session_id();
-			self.sessions.insert(session_id.clone(), Session::new(name));
-			Ok(session_id)
-		} else {
-			Err("Login failed.")
+	}
+	// Makes sure the user may open one more session under the session limit,
+	// evicting the oldest ones if the policy says so.
+	fn make_room(&mut self, name: &str) -> Result<(), Error> {
+		self.check_room(name)?;
+		let limit = self.config.session_limit;
+		if limit == 0 {
+			return Ok(());
+		}
+		let sessions = self.list_sessions(name);
+		if sessions.len() >= limit {
+			for session_id in sessions[..=sessions.len() - limit].iter() {
+				self.remove_session(session_id);
+			}
 		}
+		Ok(())
 	}
-	fn is_logged_in(&mut self, session_id: &str) -> Result<&Session, &'static str> {
+	fn is_logged_in(&mut self, session_id: &str, source: &str) -> Result<&mut Session, Error> {
 		if let Some(session) = self.sessions.get_mut(session_id) {
-			if session.last_accessed + SESSION_PERIOD > time::get_time().sec {
-				session.update();
+			if ! session.is_expired(time::get_time().sec, &self.config) {
+				session.update(source);
 				return Ok(session);
 			}
 		}
-		Err("Session not found.")
+		Err(Error::NotFound("Session not found."))
 	}
-	fn logout(&mut self, session_id: &str) -> Result<Session, &'static str> {
-		self.sessions.remove(session_id).ok_or("Session not found.")
+	// Like is_logged_in, but leaves the session untouched, so listing a user's
+	// sessions does not keep them alive.
+	fn session_info(&self, session_id: &str) -> Result<&Session, Error> {
+		match self.sessions.get(session_id) {
+			Some(session) if ! session.is_expired(time::get_time().sec, &self.config) => Ok(session),
+			_ => Err(Error::NotFound("Session not found.")),
+		}
 	}
-	fn create_user(&mut self, name: &str, pass: &str) -> Result<String, &'static str> {
-		if
-			! self.created_users.contains_key(name) &&
-			! self.updated_users.contains_key(name) &&
-			! cdb::cdb_get(self.path_users_cdb.as_str(), name).is_ok()
-		{
-			self.created_users.insert(name.to_string(), User::new(name, pass));
-			let session_id = self.create_session_id();
-			self.sessions.insert(session_id.clone(), Session::new(name));
-			Ok(session_id)
-		} else {
-			Err("User already exists.")
+	// Using a session's attributes counts as using the session.
+	fn set_attribute(&mut self, session_id: &str, source: &str, key: &str, val: &str) -> Result<(), Error> {
+		if key.is_empty() || key.len() > self.config.attribute_key_max {
+			return Err(Error::Invalid("Invalid attribute key."));
 		}
+		if val.len() > self.config.attribute_value_max {
+			return Err(Error::Invalid("Attribute value too large."));
+		}
+		let total_max = self.config.attribute_total_max;
+		let session = self.is_logged_in(session_id, source)?;
+		let old = session.attributes.get(key).map_or(0, |old| key.len() + old.len());
+		if session.attributes_size() - old + key.len() + val.len() > total_max {
+			return Err(Error::Limit("Session attributes full."));
+		}
+		session.attributes.insert(key.to_string(), val.to_string());
+		Ok(())
 	}
-	fn update_user(&mut self, name: &str, pass: &str) -> Result<(), &'static str> {
-		if let Some(user) = self.created_users.get_mut(name) {
-			user.password = pass.to_string();
-			user.updated = time::get_time().sec;
-			return Ok(());
+	fn get_attribute(&mut self, session_id: &str, source: &str, key: &str) -> Result<String, Error> {
+		let session = self.is_logged_in(session_id, source)?;
+		session.attributes.get(key).cloned().ok_or(Error::NoAttribute("Attribute not found."))
+	}
+	fn delete_attribute(&mut self, session_id: &str, source: &str, key: &str) -> Result<(), Error> {
+		let session = self.is_logged_in(session_id, source)?;
+		session.attributes.remove(key).map(|_| ()).ok_or(Error::NoAttribute("Attribute not found."))
+	}
+	// The session's attribute keys in sorted order.
+	fn attribute_keys(&mut self, session_id: &str, source: &str) -> Result<Vec<String>, Error> {
+		let session = self.is_logged_in(session_id, source)?;
+		Ok(session.attributes.keys().cloned().collect())
+	}
+	fn logout(&mut self, session_id: &str) -> Result<Session, Error> {
+		self.remove_session(session_id).ok_or(Error::NotFound("Session not found."))
+	}
+	// The user's live sessions, oldest first.
+	fn list_sessions(&self, name: &str) -> Vec<String> {
+		let name = self.username(name);
+		let now = time::get_time().sec;
+		let mut sessions: Vec<(&String, &Session)> = self.user_sessions.get(&name).into_iter().flatten()
+			.filter_map(|id| self.sessions.get(id).map(|session| (id, session)))
+			.filter(|&(_, session)| ! session.is_expired(now, &self.config))
+			.collect();
+		sessions.sort_by_key(|&(id, session)| (session.created, session.serial, id));
+		sessions.into_iter().map(|(id, _)| id.clone()).collect()
+	}
+	// Ends every session of the user and returns how many were live, which
+	// leaves out expired ones clean has yet to remove.
+	fn revoke_sessions(&mut self, name: &str) -> usize {
+		let name = self.username(name);
+		let now = time::get_time().sec;
+		let ids = self.user_sessions.remove(&name).unwrap_or_default();
+		let (sessions, config) = (&mut self.sessions, &self.config);
+		ids.iter()
+			.filter_map(|session_id| sessions.remove(session_id))
+			.filter(|session| ! session.is_expired(now, config))
+			.count()
+	}
+	// Returns the name as it will be stored: normalized, and unlike that of
+	// any existing user once that is normalized too.
+	fn check_new_user(&self, name: &str) -> Result<String, Error> {
+		let name = self.config.username_policy().normalize(name);
+		if ! self.config.username_policy().allows(name.as_str()) {
+			return Err(Error::Invalid("Invalid user name."));
 		}
-		if self.updated_users.contains_key(name) {
-			if let Some(user) = self.updated_users.get_mut(name) {
-				if ! user.is_deleted() {
-					user.password = pass.to_string();
-					user.updated = time::get_time().sec;
-					return Ok(());
-				}
-			}
-		} else if let Ok(s) = cdb::cdb_get(self.path_users_cdb.as_str(), name) {
-			let mut user = User::parse(name, s.as_str());
-			if ! user.is_deleted() {
-				user.password = pass.to_string();
-				user.updated = time::get_time().sec;
-				self.updated_users.insert(name.to_string(), user);
-				return Ok(());
+		if self.names.contains_key(&name) {
+			return Err(Error::Exists("User already exists."));
+		}
+		Ok(name)
+	}
+	// `name` comes from check_new_user, but another CREATE may have taken it
+	// since.
+	fn add_user(&mut self, name: &str, hashed: String, origin: Origin) -> Result<String, Error> {
+		if ! self.names.contains_key(name) {
+			// Sessions may outlive a deleted user of the same name.
+			self.make_room(name)?;
+			self.created_users.insert(name.to_string(), User::new(name, hashed));
+			if let Err(e) = self.journal(name) {
+				self.created_users.remove(name);
+				return Err(e);
 			}
+			self.names.insert(name.to_string(), name.to_string());
+			self.open_session(name, "create", origin)
+		} else {
+			Err(Error::Exists("User already exists."))
 		}
-		Err("User not found.")
 	}
-	fn delete_user(&mut self, name: &str) -> Result<(), &'static str> {
-		if self.created_users.contains_key(name) {
-			self.created_users.remove(name);
-			return Ok(());
+	fn set_password(&mut self, name: &str, hashed: String) -> Result<(), Error> {
+		let name = self.username(name);
+		let name = name.as_str();
+		let before = self.pending_copies(name);
+		match self.pending_user(name) {
+			Some(user) if ! user.is_deleted() => {
+				user.password = hashed;
+				user.updated = time::get_time().sec;
+			},
+			_ => return Err(Error::NotFound("User not found.")),
+		}
+		self.journal_change(name, before)?;
+		if self.config.revoke_on_password_change {
+			self.revoke_sessions(name);
 		}
-		if self.updated_users.contains_key(name) {
-			if let Some(user) = self.updated_users.get_mut(name) {
-				if ! user.is_deleted() {
+		Ok(())
+	}
+	fn delete_user(&mut self, name: &str) -> Result<(), Error> {
+		let name = self.username(name);
+		let name = name.as_str();
+		let before = self.pending_copies(name);
+		let created = self.created_users.remove(name).is_some();
+		if ! created {
+			match self.pending_user(name) {
+				Some(user) if ! user.is_deleted() => {
 					user.deleted = time::get_time().sec;
-					return Ok(());
-				}
+				},
+				_ => return Err(Error::NotFound("User not found.")),
 			}
-		} else if let Ok(s) = cdb::cdb_get(self.path_users_cdb.as_str(), name) {
-			let mut user = User::parse(name, s.as_str());
-			if ! user.is_deleted() {
-				user.deleted = time::get_time().sec;
-				self.updated_users.insert(name.to_string(), user);
-				return Ok(());
+		}
+		self.journal_change(name, before)?;
+		// A user never saved leaves no record behind, and so frees the name.
+		if created && ! self.updated_users.contains_key(name) && self.lookup_user(name).is_none() {
+			let normalized = self.config.username_policy().normalize(name);
+			if self.names.get(&normalized).map(String::as_str) == Some(name) {
+				self.names.remove(&normalized);
 			}
 		}
-		Err("User not found.")
+		if self.config.revoke_on_delete {
+			self.revoke_sessions(name);
+		}
+		Ok(())
+	}
+	fn unlock_user(&mut self, name: &str) -> Result<(), Error> {
+		let name = self.username(name);
+		let name = name.as_str();
+		let before = self.pending_copies(name);
+		match self.pending_user(name) {
+			Some(user) if ! user.is_deleted() => user.unlock(),
+			_ => return Err(Error::NotFound("User not found.")),
+		}
+		self.journal_change(name, before)
 	}
 	fn save(&mut self) -> Result<(), SaveError> {
-		let path_users_old = if self.dir.len() != 0 {
-				let mut path_buf = PathBuf::from(self.dir.clone());
-				path_buf.push(FILE_USERS_OLD);
-				path_buf.as_path().to_str().unwrap_or(FILE_USERS_OLD).to_string()
-			} else {
-				String::from(FILE_USERS_OLD)
-			};
-		let path_users_new = if self.dir.len() != 0 {
-				let mut path_buf = PathBuf::from(self.dir.clone());
-				path_buf.push(FILE_USERS_NEW);
-				path_buf.as_path().to_str().unwrap_or(FILE_USERS_NEW).to_string()
-			} else {
-				String::from(FILE_USERS_NEW)
-			};
-		let path_users_tmp = if self.dir.len() != 0 {
-				let mut path_buf = PathBuf::from(self.dir.clone());
-				path_buf.push(FILE_USERS_TMP);
-				path_buf.as_path().to_str().unwrap_or(FILE_USERS_TMP).to_string()
-			} else {
-				String::from(FILE_USERS_TMP)
-			};
-		if let Ok(_) = cdb::cdb_export(self.path_users_cdb.as_str(), path_users_old.as_str()) {
-			let of = File::open(path_users_old.as_str())?;
-			let nf = File::create(path_users_new.as_str())?;
-			let reader = BufReader::new(of);
-			let mut writer = BufWriter::new(nf);
-			for line in reader.lines() {
-				if let Ok(line) = line {
-					if let Some(pos) = line.find(char::is_whitespace) {
-						let name = &line[..pos];
-						if let Some(user) = self.updated_users.get(name) {
-							let mut buf = String::from(user.to_string());
-							buf.push('\n');
-							writer.write(buf.as_bytes())?;
-						} else {
-							let mut buf = String::from(line.as_str());
-							buf.push('\n');
-							writer.write(buf.as_bytes())?;
-						}
-					}
-				}
-			}
-			for (_, user) in self.created_users.iter() {
-				let mut buf = String::from(user.to_string());
-				buf.push('\n');
-				writer.write(buf.as_bytes())?;
-			}
-			writer.flush()?;
-			if let Ok(_) = cdb::cdb_import(path_users_tmp.as_str(), path_users_new.as_str()) {
-				fs::rename(path_users_tmp.as_str(), self.path_users_cdb.as_str())?;
-				self.created_users.clear();
-				self.updated_users.clear();
-				Ok(())
+		let path_users_old = dir_file(self.config.dir.as_str(), self.config.users_old.as_str());
+		let path_users_new = dir_file(self.config.dir.as_str(), self.config.users_new.as_str());
+		let path_users_tmp = dir_file(self.config.dir.as_str(), self.config.users_tmp.as_str());
+		if let Some(ref cdb) = self.users {
+			cdb.export(path_users_old.as_str())?;
+		} else {
+			let mut writer = File::create(path_users_old.as_str())?;
+			cdb::write_end(&mut writer)?;
+		}
+		let mut reader = BufReader::new(File::open(path_users_old.as_str())?);
+		let mut writer = BufWriter::new(File::create(path_users_new.as_str())?);
+		while let Some((key, val)) = cdb::read_record(&mut reader)? {
+			let user = str::from_utf8(&key).ok().and_then(|name| self.updated_users.get(name));
+			if let Some(user) = user {
+				cdb::write_record(&mut writer, user.name.as_bytes(), user.record().as_bytes())?;
 			} else {
-				Err(SaveError::Msg("Import failed."))
+				cdb::write_record(&mut writer, &key, &val)?;
 			}
-		} else {
-			Err(SaveError::Msg("Export failed."))
-		}
-	}
-}
-
-fn handler(session_manager: Arc<Mutex<SessionManager>>, stream: UnixStream) {
-	let mut reader = BufReader::new(&stream);
-	let mut writer = BufWriter::new(&stream);
-	let mut line = String::new();
-	if let Ok(_) = reader.read_line(&mut line) {
-		let mut sp = line.trim().split_whitespace();
-		if let Some(cmd) = sp.next() {
-			if cmd == "AUTH" {
-				let name = sp.next().unwrap_or("");
-				let pass = sp.next().unwrap_or("");
-				if let Ok(mut session_manager) = session_manager.lock() {
-					match session_manager.auth(name, pass) {
-						Ok(_) => {
-							writer.write(b"OK\r\n").unwrap();
-						},
-						Err(error) => {
-							writer.write(b"NG ").unwrap();
-							writer.write(error.as_bytes()).unwrap();
-							writer.write(b"\r\n").unwrap();
-						},
-					}
-				}
-			} else if cmd == "LOGIN" {
-				let name = sp.next().unwrap_or("");
-				let pass = sp.next().unwrap_or("");
-				if let Ok(mut session_manager) = session_manager.lock() {
-					match session_manager.login(name, pass) {
-						Ok(session_id) => {
-							writer.write(b"OK ").unwrap();
-							writer.write(session_id.as_bytes()).unwrap();
-							writer.write(b"\r\n").unwrap();
-						},
-						Err(error) => {
-							writer.write(b"NG ").unwrap();
-							writer.write(error.as_bytes()).unwrap();
-							writer.write(b"\r\n").unwrap();
-						},
-					}
-				}
-			} else if cmd == "SESSION" {
-				let session_id = sp.next().unwrap_or("");
-				if let Ok(mut session_manager) = session_manager.lock() {
-					match session_manager.is_logged_in(session_id) {
-						Ok(session) => {
-							writer.write(b"OK ").unwrap();
-							writer.write(session.name.as_bytes()).unwrap();
-							writer.write(b"\r\n").unwrap();
-						},
-						Err(error) => {
-							writer.write(b"NG ").unwrap();
-							writer.write(error.as_bytes()).unwrap();
-							writer.write(b"\r\n").unwrap();
-						},
-					}
-				}
-			} else if cmd == "LOGOUT" {
-				let session_id = sp.next().unwrap_or("");
-				if let Ok(mut session_manager) = session_manager.lock() {
-					match session_manager.logout(session_id) {
-						Ok(session) => {
-							writer.write(b"OK ").unwrap();
-							writer.write(session.name.as_bytes()).unwrap();
-							writer.write(b"\r\n").unwrap();
-						},
-						Err(error) => {
-							writer.write(b"NG ").unwrap();
-							writer.write(error.as_bytes()).unwrap();
-							writer.write(b"\r\n").unwrap();
-						},
-					}
-				}
-			} else if cmd == "CREATE" {
-				let name = sp.next().unwrap_or("");
-				let pass = sp.next().unwrap_or("");
-				if let Ok(mut session_manager) = session_manager.lock() {
-					match session_manager.create_user(name, pass) {
-						Ok(session_id) => {
-							writer.write(b"OK ").unwrap();
-							writer.write(session_id.as_bytes()).unwrap();
-							writer.write(b"\r\n").unwrap();
-						},
-						Err(error) => {
-							writer.write(b"NG ").unwrap();
-							writer.write(error.as_bytes()).unwrap();
-							writer.write(b"\r\n").unwrap();
-						},
-					}
-				}
-			} else if cmd == "UPDATE" {
-				let name = sp.next().unwrap_or("");
-				let pass = sp.next().unwrap_or("");
-				if let Ok(mut session_manager) = session_manager.lock() {
-					match session_manager.update_user(name, pass) {
-						Ok(_) => {
-							writer.write(b"OK\r\n").unwrap();
-						},
-						Err(error) => {
-							writer.write(b"NG ").unwrap();
-							writer.write(error.as_bytes()).unwrap();
-							writer.write(b"\r\n").unwrap();
-						},
-					}
+		}
+		for user in self.created_users.values() {
+			cdb::write_record(&mut writer, user.name.as_bytes(), user.record().as_bytes())?;
+		}
+		cdb::write_end(&mut writer)?;
+		writer.flush()?;
+		cdb::cdb_import(path_users_tmp.as_str(), path_users_new.as_str())?;
+		fs::rename(path_users_tmp.as_str(), self.path_users_cdb.as_str())?;
+		self.users = Some(cdb::Cdb::open(self.path_users_cdb.as_str())?);
+		self.created_users.clear();
+		self.updated_users.clear();
+		self.journal.clear()?;
+		Ok(())
+	}
+	fn save_sessions(&self) -> Result<(), SaveError> {
+		let path_sessions_cdb = dir_file(self.config.dir.as_str(), FILE_SESSIONS_CDB);
+		let path_sessions_tmp = dir_file(self.config.dir.as_str(), FILE_SESSIONS_TMP);
+		let mut cdb_make = cdb::CdbMake::create(path_sessions_tmp.as_str())?;
+		for (session_id, session) in self.sessions.iter() {
+			cdb_make.add(session_id.as_bytes(), session.record().as_bytes())?;
+		}
+		cdb_make.finish()?;
+		fs::rename(path_sessions_tmp.as_str(), path_sessions_cdb.as_str())?;
+		Ok(())
+	}
+}
+
+fn lock_manager(session_manager: &Mutex<SessionManager>) -> Result<MutexGuard<'_, SessionManager>, Error> {
+	session_manager.lock().map_err(|_| Error::Internal("Internal error."))
+}
+
+// Argon2 is deliberately slow, so the commands that hash or verify passwords
+// hold the lock only while they read and update the user.
+fn auth(session_manager: &Mutex<SessionManager>, name: &str, pass: &str) -> Result<(), Error> {
+	let mut check = lock_manager(session_manager)?.begin_check(name, "Authentication failed.")?;
+	check.verify(pass);
+	lock_manager(session_manager)?.finish_check(check, false, "Authentication failed.")
+}
+
+fn login(session_manager: &Mutex<SessionManager>, name: &str, pass: &str, origin: Origin) -> Result<String, Error> {
+	let mut check = lock_manager(session_manager)?.begin_check(name, "Login failed.")?;
+	check.verify(pass);
+	let name = check.name.clone();
+	let mut session_manager = lock_manager(session_manager)?;
+	session_manager.finish_check(check, true, "Login failed.")?;
+	session_manager.make_room(name.as_str())?;
+	session_manager.open_session(name.as_str(), "password", origin)
+}
+
+fn create_user(session_manager: &Mutex<SessionManager>, name: &str, pass: &str, origin: Origin) -> Result<String, Error> {
+	let name = lock_manager(session_manager)?.check_new_user(name)?;
+	let hashed = password::hash(pass)?;
+	lock_manager(session_manager)?.add_user(name.as_str(), hashed, origin)
+}
+
+fn update_user(session_manager: &Mutex<SessionManager>, name: &str, pass: &str) -> Result<(), Error> {
+	let hashed = password::hash(pass)?;
+	lock_manager(session_manager)?.set_password(name, hashed)
+}
+
+fn load_sessions(path: &str, config: &config::Config) -> Result<HashMap<String, Session>, cdb::CDBError> {
+	let mut sessions = HashMap::new();
+	if let Some(cdb) = cdb::Cdb::open_if_exists(path)? {
+		let now = time::get_time().sec;
+		for record in cdb.iter()? {
+			let (key, val) = record?;
+			let session_id = str::from_utf8(key).ok();
+			let session = str::from_utf8(val).ok().and_then(Session::parse);
+			if let (Some(session_id), Some(session)) = (session_id, session) {
+				if ! session.is_expired(now, config) {
+					sessions.insert(session_id.to_string(), session);
 				}
-			} else if cmd == "DELETE" {
-				let name = sp.next().unwrap_or("");
-				if let Ok(mut session_manager) = session_manager.lock() {
-					match session_manager.delete_user(name) {
-						Ok(_) => {
-							writer.write(b"OK\r\n").unwrap();
-						},
-						Err(error) => {
-							writer.write(b"NG ").unwrap();
-							writer.write(error.as_bytes()).unwrap();
-							writer.write(b"\r\n").unwrap();
-						},
-					}
+			}
+		}
+	}
+	Ok(sessions)
+}
+
+fn ok(result: &str) -> String {
+	if result.is_empty() {
+		String::from("OK\r\n")
+	} else {
+		format!("OK {}\r\n", result)
+	}
+}
+
+fn ng(error: &str) -> String {
+	format!("NG {}\r\n", error)
+}
+
+// Credentials of the process on the other end of a connection. The pid is 0
+// where the platform doesn't report it.
+#[derive(Clone, Copy)]
+struct Peer {
+	uid: u32,
+	gid: u32,
+	pid: i32,
+}
+
+#[cfg(target_os = "linux")]
+fn peer_cred(stream: &UnixStream) -> Option<Peer> {
+	let mut cred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+	let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+	let ret = unsafe {
+		libc::getsockopt(
+			stream.as_raw_fd(),
+			libc::SOL_SOCKET,
+			libc::SO_PEERCRED,
+			&mut cred as *mut libc::ucred as *mut libc::c_void,
+			&mut len)
+	};
+	if ret != 0 {
+		return None;
+	}
+	Some(Peer {
+		uid: cred.uid,
+		gid: cred.gid,
+		pid: cred.pid,
+	})
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "dragonfly", target_os = "openbsd", target_os = "netbsd"))]
+fn peer_cred(stream: &UnixStream) -> Option<Peer> {
+	let mut uid: libc::uid_t = 0;
+	let mut gid: libc::gid_t = 0;
+	if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+		return None;
+	}
+	Some(Peer {
+		uid,
+		gid,
+		pid: 0,
+	})
+}
+
+// Without a way to ask, every connection falls under acl.default.
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "dragonfly", target_os = "openbsd", target_os = "netbsd")))]
+fn peer_cred(_stream: &UnixStream) -> Option<Peer> {
+	None
+}
+
+// Every command with the least and most arguments it takes.
+const COMMANDS: &[(&str, usize, usize)] = &[
+	("AUTH", 2, 2),
+	("LOGIN", 2, 3),
+	("SESSION", 1, 1),
+	("SESSIONINFO", 1, 1),
+	("LOGOUT", 1, 1),
+	("CREATE", 2, 3),
+	("UPDATE", 2, 2),
+	("DELETE", 1, 1),
+	("UNLOCK", 1, 1),
+	("SAVE", 0, 0),
+	("LIST", 1, 1),
+	("REVOKE-ALL", 1, 1),
+	("SET", 3, 3),
+	("GET", 2, 2),
+	("DEL", 2, 2),
+	("KEYS", 1, 1),
+];
+
+// v1 failures are "NG <message>"; v2 adds a symbolic code: "NG <CODE> <message>".
+fn fail(version: u32, code: &str, message: &str) -> String {
+	if version >= 2 {
+		format!("NG {} {}\r\n", code, message)
+	} else {
+		ng(message)
+	}
+}
+
+// HELLO [version] selects the protocol version for the rest of the connection,
+// capped at the newest one this daemon speaks, and replies with the version in
+// effect. Connections start at version 1.
+fn hello(version: &mut u32, args: &[String]) -> String {
+	if args.len() > 1 {
+		return fail(*version, "SYNTAX", "Wrong number of arguments.");
+	}
+	match args.first().map(|v| v.parse::<u32>()) {
+		None => {},
+		Some(Ok(v)) if v >= 1 => *version = cmp::min(v, PROTOCOL_VERSION),
+		_ => return fail(*version, "BAD_VERSION", "Unsupported protocol version."),
+	}
+	ok(version.to_string().as_str())
+}
+
+// Who is on the other end of a connection, as far as the ACL is concerned: a
+// local process, or a TLS client with the names of its verified certificate.
+enum Caller {
+	Unix(Option<Peer>),
+	Tls(Vec<String>),
+}
+
+impl Caller {
+	fn allowed(&self, acl: &config::Acl, cmd: &str) -> bool {
+		match *self {
+			Caller::Unix(peer) => acl.allows(peer.map(|p| p.uid), peer.map(|p| p.gid), cmd),
+			Caller::Tls(ref names) => acl.allows_network(names, cmd),
+		}
+	}
+}
+
+fn execute(session_manager: &Mutex<SessionManager>, caller: &Caller, source: &str, version: u32, words: &[String]) -> String {
+	let (cmd, args) = match words.split_first() {
+			Some(split) => split,
+			// Every request gets a reply, or pipelined ones would lose their pairing.
+			None if version >= 2 => return fail(version, "SYNTAX", "Empty request."),
+			None => return String::from("ERROR\r\n"),
+		};
+	let internal = || fail(version, "INTERNAL", "Internal error.");
+	let cmd = cmd.as_str();
+	let (min_args, max_args) = match COMMANDS.iter().find(|&&(c, _, _)| c == cmd) {
+			Some(&(_, min_args, max_args)) => (min_args, max_args),
+			None => {
+				if version >= 2 {
+					return fail(version, "UNKNOWN_COMMAND", "Unknown command.");
 				}
-			} else if cmd == "SAVE" {
-				if let Ok(mut session_manager) = session_manager.lock() {
-					match session_manager.save() {
-						Ok(_) => {
-							writer.write(b"OK\r\n").unwrap();
-						},
-						Err(error) => {
-							match error {
-								SaveError::Io(e) => {
-									writer.write(b"NG ").unwrap();
-									writer.write(e.description().as_bytes()).unwrap();
-									writer.write(b"\r\n").unwrap();
-								},
-								SaveError::Msg(m) => {
-									writer.write(b"NG ").unwrap();
-									writer.write(m.as_bytes()).unwrap();
-									writer.write(b"\r\n").unwrap();
-								},
-							}
-						},
-					}
+				return String::from("ERROR\r\n");
+			},
+		};
+	let allowed = match session_manager.lock() {
+			Ok(session_manager) => caller.allowed(&session_manager.config.acl, cmd),
+			Err(_) => return internal(),
+		};
+	if ! allowed {
+		if version >= 2 {
+			return fail(version, "DENIED", "Permission denied.");
+		}
+		return String::from("DENIED\r\n");
+	}
+	// Version 1 fills in missing arguments with empty ones and ignores extra
+	// ones, as it always has.
+	if version >= 2 && (args.len() < min_args || args.len() > max_args) {
+		return fail(version, "SYNTAX", "Wrong number of arguments.");
+	}
+	let mut sp = args.iter().map(String::as_str);
+	let mut arg = || sp.next().unwrap_or("");
+	// The password commands take the lock themselves; the rest hold it
+	// throughout.
+	let result = match cmd {
+		"AUTH" => {
+			let (name, pass) = (arg(), arg());
+			auth(session_manager, name, pass).map(|_| String::new())
+		},
+		"LOGIN" => {
+			let (name, pass, label) = (arg(), arg(), arg());
+			login(session_manager, name, pass, Origin { source, label })
+		},
+		"CREATE" => {
+			let (name, pass, label) = (arg(), arg(), arg());
+			create_user(session_manager, name, pass, Origin { source, label })
+		},
+		"UPDATE" => {
+			let (name, pass) = (arg(), arg());
+			update_user(session_manager, name, pass).map(|_| String::new())
+		},
+		_ => {
+			let mut session_manager = match session_manager.lock() {
+					Ok(session_manager) => session_manager,
+					Err(_) => return internal(),
+				};
+			match cmd {
+				"SESSION" => session_manager.is_logged_in(arg(), source).map(|session| session.name.clone()),
+				"SESSIONINFO" => session_manager.session_info(arg()).map(Session::info),
+				"LOGOUT" => session_manager.logout(arg()).map(|session| session.name),
+				"DELETE" => session_manager.delete_user(arg()).map(|_| String::new()),
+				"UNLOCK" => session_manager.unlock_user(arg()).map(|_| String::new()),
+				"LIST" => Ok(session_manager.list_sessions(arg()).join(" ")),
+				"REVOKE-ALL" => Ok(session_manager.revoke_sessions(arg()).to_string()),
+				// Values and keys may hold any text, so they are sent back quoted.
+				"SET" => {
+					let (session_id, key, val) = (arg(), arg(), arg());
+					session_manager.set_attribute(session_id, source, key, val).map(|_| String::new())
+				},
+				"GET" => {
+					let (session_id, key) = (arg(), arg());
+					session_manager.get_attribute(session_id, source, key).map(|val| args::quote(val.as_str()))
+				},
+				"DEL" => {
+					let (session_id, key) = (arg(), arg());
+					session_manager.delete_attribute(session_id, source, key).map(|_| String::new())
+				},
+				"KEYS" => session_manager.attribute_keys(arg(), source)
+					.map(|keys| keys.iter().map(|key| args::quote(key)).collect::<Vec<String>>().join(" ")),
+				"SAVE" => {
+					return match session_manager.save() {
+						Ok(_) => ok(""),
+						Err(error) => fail(version, "INTERNAL", error.to_string().as_str()),
+					};
+				},
+				_ => return String::from("ERROR\r\n"),
+			}
+		},
+	};
+	match result {
+		Ok(result) => ok(result.as_str()),
+		Err(error) => fail(version, error.code(), error.message()),
+	}
+}
+
+// Reads one request line into `line`. A line longer than `max` bytes is
+// consumed through its newline but kept only up to max + 1 bytes, for the
+// caller to reject.
+fn read_request<R: BufRead>(reader: &mut R, line: &mut Vec<u8>, max: usize) -> io::Result<usize> {
+	line.clear();
+	let len = reader.by_ref().take(max as u64 + 1).read_until(b'\n', line)?;
+	if len > max && line.last() != Some(&b'\n') {
+		loop {
+			let (done, used) = {
+				let buf = reader.fill_buf()?;
+				match buf.iter().position(|&b| b == b'\n') {
+					Some(pos) => (true, pos + 1),
+					None => (buf.is_empty(), buf.len()),
 				}
+			};
+			reader.consume(used);
+			if done {
+				break;
+			}
+		}
+	}
+	Ok(len)
+}
+
+// Serves commands until the client sends QUIT or closes the connection.
+// Replies are buffered while pipelined requests are still pending and are
+// written once the input runs dry, so they always arrive in request order.
+fn handler<S: Read + Write>(session_manager: &Mutex<SessionManager>, caller: &Caller, source: &str, stream: S) {
+	let mut reader = BufReader::new(stream);
+	let mut out: Vec<u8> = Vec::new();
+	let mut line = Vec::new();
+	let mut version = 1;
+	loop {
+		let request_max = match session_manager.lock() {
+			Ok(session_manager) => session_manager.config.request_max,
+			Err(_) => break,
+		};
+		match read_request(&mut reader, &mut line, request_max) {
+			Ok(0) | Err(_) => break,
+			Ok(_) => {},
+		}
+		let words = if line.len() > request_max {
+				Err("Request is too long.")
 			} else {
-				writer.write(b"ERROR\r\n").unwrap();
+				// Quoting is part of version 2; version 1 lines split on blanks.
+				str::from_utf8(&line).map_err(|_| "Request is not UTF-8.").and_then(|line| if version >= 2 {
+					args::split(line)
+				} else {
+					Ok(line.split_whitespace().map(String::from).collect())
+				})
+			};
+		let response = match words {
+			Err(error) => fail(version, "SYNTAX", error),
+			Ok(ref words) if words.first().map(String::as_str) == Some("QUIT") => {
+				out.extend_from_slice(ok("").as_bytes());
+				break;
+			},
+			Ok(ref words) if words.first().map(String::as_str) == Some("HELLO") => hello(&mut version, &words[1..]),
+			Ok(ref words) => execute(session_manager, caller, source, version, words),
+		};
+		out.extend_from_slice(response.as_bytes());
+		if reader.buffer().is_empty() {
+			let stream = reader.get_mut();
+			if stream.write_all(&out).and_then(|_| stream.flush()).is_err() {
+				return;
 			}
+			out.clear();
 		}
 	}
+	let stream = reader.get_mut();
+	let _ = stream.write_all(&out).and_then(|_| stream.flush());
+}
+
+fn serve_unix(session_manager: Arc<Mutex<SessionManager>>, server: Arc<server::Server>, listener: UnixListener) {
+	for stream in listener.incoming().flatten() {
+		let closer = match stream.try_clone() {
+			Ok(s) => s,
+			Err(_) => continue,
+		};
+		let guard = match server::Server::register(&server, Box::new(move || { let _ = closer.shutdown(Shutdown::Read); })) {
+			Some(guard) => guard,
+			None => break,
+		};
+		let sm = session_manager.clone();
+		thread::spawn(move || {
+			let peer = peer_cred(&stream);
+			let source = match peer {
+				Some(peer) if peer.pid == 0 => format!("unix:uid={}", peer.uid),
+				Some(peer) => format!("unix:uid={},pid={}", peer.uid, peer.pid),
+				None => String::from("unix"),
+			};
+			handler(&sm, &Caller::Unix(peer), source.as_str(), stream);
+			drop(guard);
+		});
+	}
+}
+
+fn serve_tcp(session_manager: Arc<Mutex<SessionManager>>, server: Arc<server::Server>, listener: TcpListener, tls: Arc<rustls::ServerConfig>) {
+	for stream in listener.incoming().flatten() {
+		let closer = match stream.try_clone() {
+			Ok(s) => s,
+			Err(_) => continue,
+		};
+		let guard = match server::Server::register(&server, Box::new(move || { let _ = closer.shutdown(Shutdown::Read); })) {
+			Some(guard) => guard,
+			None => break,
+		};
+		let timeout = match session_manager.lock() {
+			Ok(session_manager) => Some(Duration::from_secs(session_manager.config.tcp_timeout)),
+			Err(_) => break,
+		};
+		let sm = session_manager.clone();
+		let tls = tls.clone();
+		thread::spawn(move || {
+			let mut stream = stream;
+			let _ = stream.set_nodelay(true);
+			let _ = stream.set_read_timeout(timeout).and_then(|_| stream.set_write_timeout(timeout));
+			let source = match stream.peer_addr() {
+				Ok(addr) => format!("tcp:{}", addr),
+				Err(_) => String::from("tcp"),
+			};
+			if let Ok(mut conn) = rustls::ServerConnection::new(tls) {
+				if tls::handshake(&mut conn, &mut stream).is_ok() {
+					let caller = Caller::Tls(tls::client_names(&conn));
+					handler(&sm, &caller, source.as_str(), rustls::StreamOwned::new(conn, stream));
+				}
+			}
+			drop(guard);
+		});
+	}
 }
 
 fn maintenance(session_manager: Arc<Mutex<SessionManager>>) {
 	loop {
+		let mut interval = 0;
 		if let Ok(mut session_manager) = session_manager.lock() {
 			session_manager.clean();
 			if
@@ -591,52 +1257,285 @@ fn maintenance(session_manager: Arc<Mutex<SessionManager>>) {
 			{
 				let _ = session_manager.save();
 			}
+			let _ = session_manager.save_sessions();
+			interval = session_manager.config.maintenance_interval;
 		}
-		thread::sleep(Duration::from_secs(600));
+		thread::sleep(Duration::from_secs(interval));
 	}
 }
 
-fn get_args() -> (String, String) {
-	let mut path_sock = String::new();
-	let mut dir_user = String::new();
-	let mut flag_path_sock = false;
-	let mut flag_dir_user = false;
-	for arg in env::args() {
-		if flag_path_sock {
-			path_sock = arg;
-			flag_path_sock = false;
-			continue;
-		}
-		if flag_dir_user {
-			dir_user = arg;
-			flag_dir_user = false;
-			continue;
+// Holds the data directory lock for the life of the process, with the pid
+// written in the lock file.
+fn lock_dir(dir: &str) -> Result<File, String> {
+	let mut file = config::lock_dir(dir, "another sessiond is using this directory")?;
+	file.set_len(0).and_then(|_| writeln!(file, "{}", process::id()))
+		.map_err(|e| format!("{}: {}", dir_file(dir, config::FILE_LOCK), e))?;
+	Ok(file)
+}
+
+// Binds the socket, replacing a socket file left behind by a crashed daemon.
+// A path that still accepts connections belongs to a live daemon.
+fn bind_socket(path: &str) -> Result<UnixListener, String> {
+	if let Ok(metadata) = fs::symlink_metadata(path) {
+		if ! metadata.file_type().is_socket() {
+			return Err(format!("{}: exists and is not a socket", path));
 		}
-		if arg == "-sock" {
-			flag_path_sock = true;
-			continue;
+		match UnixStream::connect(path) {
+			Ok(_) => return Err(format!("{}: another sessiond is listening", path)),
+			Err(ref e) if e.kind() == ErrorKind::ConnectionRefused => {
+				fs::remove_file(path).map_err(|e| format!("{}: {}", path, e))?;
+			},
+			Err(e) => return Err(format!("{}: {}", path, e)),
 		}
-		if arg == "-dir" {
-			flag_dir_user = true;
-			continue;
+	}
+	UnixListener::bind(path).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn bind_tcp(addr: &str) -> Result<(TcpListener, SocketAddr), String> {
+	let listener = TcpListener::bind(addr).map_err(|e| format!("{}: {}", addr, e))?;
+	let mut local = listener.local_addr().map_err(|e| format!("{}: {}", addr, e))?;
+	if local.ip().is_unspecified() {
+		local.set_ip(if local.is_ipv4() { IpAddr::V4(Ipv4Addr::LOCALHOST) } else { IpAddr::V6(Ipv6Addr::LOCALHOST) });
+	}
+	Ok((listener, local))
+}
+
+fn signal_handler(session_manager: Arc<Mutex<SessionManager>>, server: Arc<server::Server>, mut signals: Signals) {
+	for signal in signals.forever() {
+		if signal == SIGHUP {
+			match config::load() {
+				Ok(config) => {
+					if let Ok(mut session_manager) = session_manager.lock() {
+						session_manager.reconfigure(config);
+					}
+				},
+				Err(e) => eprintln!("sessiond: reload failed: {}", e),
+			}
+		} else {
+			server.stop();
+			return;
 		}
 	}
-	return (path_sock, dir_user);
+}
+
+fn exit_with<T>(e: String) -> T {
+	eprintln!("sessiond: {}", e);
+	process::exit(1);
 }
 
 fn main() {
-	let (path, dir) = get_args();
+	let config = match config::load() {
+		Ok(config) => config,
+		Err(e) => {
+			eprintln!("sessiond: {}", e);
+			process::exit(2);
+		},
+	};
+	let path = config.socket.clone();
+	let tcp_listen = config.tcp_listen.clone();
+	let http_listen = config.http_listen.clone();
+	let tls = if !tcp_listen.is_empty() {
+			Some(tls::server_config(config.tls_cert.as_str(), config.tls_key.as_str(), config.tls_client_ca.as_str()).unwrap_or_else(exit_with))
+		} else {
+			None
+		};
 
-	let session_manager = Arc::new(Mutex::new(SessionManager::new(dir)));
+	let _lock = lock_dir(config.dir.as_str()).unwrap_or_else(exit_with);
+
+	let session_manager = Arc::new(Mutex::new(SessionManager::new(config).unwrap_or_else(exit_with)));
+	let server = server::Server::new();
+	let mut listeners = Vec::new();
+
+	if !path.is_empty() {
+		let listener = bind_socket(path.as_str()).unwrap_or_else(exit_with);
+		let wake = path.clone();
+		server.add_waker(Box::new(move || { let _ = UnixStream::connect(wake.as_str()); }));
+		let sm = session_manager.clone();
+		let sv = server.clone();
+		listeners.push(thread::spawn(move || serve_unix(sm, sv, listener)));
+	}
+	if let Some(tls) = tls {
+		let (listener, local) = bind_tcp(tcp_listen.as_str()).unwrap_or_else(exit_with);
+		server.add_waker(Box::new(move || { let _ = TcpStream::connect(local); }));
+		let sm = session_manager.clone();
+		let sv = server.clone();
+		listeners.push(thread::spawn(move || serve_tcp(sm, sv, listener, tls)));
+	}
+	if !http_listen.is_empty() {
+		let listener = http::bind(http_listen.as_str()).unwrap_or_else(exit_with);
+		let wake = listener.clone();
+		server.add_waker(Box::new(move || wake.unblock()));
+		let sm = session_manager.clone();
+		let sv = server.clone();
+		listeners.push(thread::spawn(move || http::serve(sm, sv, listener)));
+	}
 
 	let sm = session_manager.clone();
 	thread::spawn(move || maintenance(sm));
 
-	let listener = UnixListener::bind(if path.len() != 0 { path.as_str() } else { FILE_SOCKET }).unwrap();
-	for stream in listener.incoming() {
-		if let Ok(stream) = stream {
-			let sm = session_manager.clone();
-			thread::spawn(move || handler(sm, stream));
+	let signals = Signals::new([SIGHUP, SIGTERM, SIGINT]).unwrap();
+	let sm = session_manager.clone();
+	let sv = server.clone();
+	thread::spawn(move || signal_handler(sm, sv, signals));
+
+	for listener in listeners {
+		let _ = listener.join();
+	}
+
+	server.wait();
+	if let Ok(mut session_manager) = session_manager.lock() {
+		if
+			! session_manager.created_users.is_empty() ||
+			! session_manager.updated_users.is_empty()
+		{
+			if let Err(e) = session_manager.save() {
+				eprintln!("sessiond: save failed: {}", e);
+			}
+		}
+		if let Err(e) = session_manager.save_sessions() {
+			eprintln!("sessiond: session snapshot failed: {}", e);
+		}
+	}
+	if !path.is_empty() {
+		let _ = fs::remove_file(path.as_str());
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::env;
+
+	// A SessionManager started on a fresh directory holding `users` in
+	// users.cdb and `entries` in the journal.
+	fn manager(name: &str, users: &[(&str, &str)], entries: &[(&str, &str)]) -> SessionManager {
+		let mut dir = env::temp_dir();
+		dir.push(format!("sessiond-main-{}-{}", process::id(), name));
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+		let config = config::Config {
+			dir: dir.to_str().unwrap().to_string(),
+			..Default::default()
+		};
+		let mut cdb_make = cdb::CdbMake::create(dir_file(config.dir.as_str(), config.users_cdb.as_str()).as_str()).unwrap();
+		for &(name, record) in users {
+			cdb_make.add(name.as_bytes(), record.as_bytes()).unwrap();
+		}
+		cdb_make.finish().unwrap();
+		let (mut journal, _) = journal::Journal::open(dir_file(config.dir.as_str(), config.users_journal.as_str()).as_str()).unwrap();
+		for &(name, entry) in entries {
+			journal.append(name.as_bytes(), entry.as_bytes()).unwrap();
+		}
+		SessionManager::new(config).unwrap()
+	}
+
+	#[test]
+	fn journal_is_replayed() {
+		let session_manager = manager("replay", &[("bob", "one 1 0 0 0 0 0 0 0")], &[
+			("carol", "C two 2 0 0 0 0 0 0 0"),
+			("dave", "C three 3 0 0 0 0 0 0 0"),
+			("dave", "D"),
+			("bob", "U four 1 4 0 0 0 0 0 0"),
+		]);
+		assert_eq!(session_manager.created_users.keys().collect::<Vec<_>>(), vec!["carol"]);
+		assert_eq!(session_manager.updated_users.keys().collect::<Vec<_>>(), vec!["bob"]);
+		assert_eq!(session_manager.updated_users["bob"].password, "four");
+		let _ = fs::remove_dir_all(session_manager.config.dir.as_str());
+	}
+
+	// As left by a crash after save installed users.cdb but before it cleared
+	// the journal.
+	#[test]
+	fn creations_already_saved_are_replayed_as_updates() {
+		let mut session_manager = manager("saved", &[("carol", "two 2 0 0 0 0 0 0 0")], &[
+			("carol", "C two 2 0 0 0 0 0 0 0"),
+		]);
+		assert!(session_manager.created_users.is_empty());
+		session_manager.pending_user("carol").unwrap().password = String::from("five");
+		assert!(session_manager.save().is_ok());
+		let cdb = session_manager.users.as_ref().unwrap();
+		let records: Vec<cdb::Record> = cdb.iter().unwrap().map(|r| r.map(|(k, v)| (k.to_vec(), v.to_vec())).unwrap()).collect();
+		assert_eq!(records.len(), 1);
+		assert_eq!(session_manager.lookup_user("carol").unwrap().password, "five");
+		let _ = fs::remove_dir_all(session_manager.config.dir.as_str());
+	}
+
+	#[test]
+	fn names_stored_before_normalization_stay_reachable() {
+		let mut session_manager = manager("legacy", &[("Bob", "one 1 0 0 0 0 0 0 0"), ("carol", "two 1 0 0 0 0 0 0 0")], &[
+			("Dave", "C three 3 0 0 0 0 0 0 0"),
+		]);
+		for name in ["Bob", "bob", "BOB"] {
+			assert_eq!(session_manager.username(name), "Bob");
+			assert!(matches!(session_manager.check_new_user(name), Err(Error::Exists(_))));
+		}
+		assert_eq!(session_manager.username("CAROL"), "carol");
+		assert_eq!(session_manager.username("dave"), "Dave");
+		assert!(matches!(session_manager.check_new_user("DAVE"), Err(Error::Exists(_))));
+		assert!(session_manager.delete_user("dave").is_ok());
+		let name = session_manager.check_new_user("DAVE").unwrap();
+		assert!(session_manager.add_user(name.as_str(), String::from("four"), Origin { source: "", label: "" }).is_ok());
+		assert_eq!(session_manager.username("Dave"), "dave");
+		let _ = fs::remove_dir_all(session_manager.config.dir.as_str());
+	}
+
+	#[test]
+	fn unknown_and_deleted_users_are_checked_against_the_dummy_hash() {
+		let mut session_manager = manager("dummy", &[("bob", "one 1 0 5 0 0 0 0 0")], &[]);
+		for name in ["bob", "nobody"] {
+			let mut check = session_manager.begin_check(name, "Login failed.").unwrap();
+			assert_eq!(check.stored, password::DUMMY);
+			check.verify("one");
+			assert!(! check.verified);
+			assert!(matches!(session_manager.finish_check(check, true, "Login failed."), Err(Error::BadCredentials(_))));
+		}
+		assert!(session_manager.created_users.is_empty());
+		let _ = fs::remove_dir_all(session_manager.config.dir.as_str());
+	}
+
+	#[test]
+	fn overlapping_checks_of_a_legacy_password_both_pass() {
+		let mut session_manager = manager("upgrade", &[("bob", "one 1 0 0 0 0 0 0 0")], &[]);
+		let mut first = session_manager.begin_check("bob", "Login failed.").unwrap();
+		let mut second = session_manager.begin_check("bob", "Login failed.").unwrap();
+		first.verify("one");
+		second.verify("one");
+		assert!(session_manager.finish_check(first, true, "Login failed.").is_ok());
+		let hashed = session_manager.pending_user("bob").unwrap().password.clone();
+		assert!(password::is_hashed(hashed.as_str()));
+		assert!(session_manager.finish_check(second, true, "Login failed.").is_ok());
+		assert_eq!(session_manager.pending_user("bob").unwrap().password, hashed);
+		// A real change still fails a check begun before it.
+		let mut third = session_manager.begin_check("bob", "Login failed.").unwrap();
+		third.verify("one");
+		session_manager.pending_user("bob").unwrap().password = password::hash("two").unwrap();
+		assert!(matches!(session_manager.finish_check(third, true, "Login failed."), Err(Error::BadCredentials(_))));
+		let _ = fs::remove_dir_all(session_manager.config.dir.as_str());
+	}
+
+	#[test]
+	fn revoking_counts_only_live_sessions() {
+		let mut session_manager = manager("revoke", &[("bob", "one 1 0 0 0 0 0 0 0")], &[]);
+		let origin = Origin { source: "", label: "" };
+		let live = session_manager.open_session("bob", "password", origin).unwrap();
+		let expired = session_manager.open_session("bob", "password", origin).unwrap();
+		session_manager.sessions.get_mut(&expired).unwrap().last_accessed -= session_manager.config.session_period;
+		assert_eq!(session_manager.list_sessions("bob"), vec![live]);
+		assert_eq!(session_manager.revoke_sessions("bob"), 1);
+		assert!(session_manager.sessions.is_empty());
+		let _ = fs::remove_dir_all(session_manager.config.dir.as_str());
+	}
+
+	#[test]
+	fn base64url_test_vectors() {
+		// RFC 4648, section 10, without the padding.
+		let vectors = [("", ""), ("f", "Zg"), ("fo", "Zm8"), ("foo", "Zm9v"), ("foob", "Zm9vYg"), ("fooba", "Zm9vYmE"), ("foobar", "Zm9vYmFy")];
+		for &(bytes, encoded) in vectors.iter() {
+			assert_eq!(bytes_to_base64url(bytes.as_bytes()), encoded);
 		}
+		assert_eq!(bytes_to_base64url(&[0xfb, 0xff, 0xbf]), "-_-_");
+		let id = bytes_to_base64url(&[0xff; SESSION_ID_BYTES]);
+		assert_eq!(id.len(), 43);
+		assert!(id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_'));
 	}
 }
@@ -0,0 +1,45 @@
+use argon2::Argon2;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use rand::{OsRng, Rng};
+
+// Stored passwords are PHC strings ("$argon2id$v=19$m=...,t=...,p=...$salt$hash").
+// Anything else is a legacy plaintext record.
+// What checks for a missing or deleted user verify against, so that they cost
+// one Argon2 verify like any other and do not tell which users exist.
+pub const DUMMY: &str = "$argon2id$v=19$m=19456,t=2,p=1$c2Vzc2lvbmQtZHVtbXkhIQ$eDjMlG3gmK/eWZnyYJxkAtvEqlviOlhrX+zfmnQAVM8";
+
+pub fn is_hashed(stored: &str) -> bool {
+	stored.starts_with('$') && PasswordHash::new(stored).is_ok()
+}
+
+pub fn hash(pass: &str) -> Result<String, &'static str> {
+	let mut bytes: [u8; 16] = [0; 16];
+	let mut rng = OsRng::new().map_err(|_| "Password hashing failed.")?;
+	rng.fill_bytes(&mut bytes);
+	let salt = SaltString::encode_b64(&bytes).map_err(|_| "Password hashing failed.")?;
+	Argon2::default()
+		.hash_password(pass.as_bytes(), &salt)
+		.map(|h| h.to_string())
+		.map_err(|_| "Password hashing failed.")
+}
+
+pub fn verify(stored: &str, pass: &str) -> bool {
+	if let Ok(parsed) = PasswordHash::new(stored) {
+		Argon2::default().verify_password(pass.as_bytes(), &parsed).is_ok()
+	} else {
+		// Legacy plaintext checks take as long as hashed ones.
+		let _ = verify(DUMMY, pass);
+		constant_time_eq(stored.as_bytes(), pass.as_bytes())
+	}
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+	if a.len() != b.len() {
+		return false;
+	}
+	let mut diff: u8 = 0;
+	for (x, y) in a.iter().zip(b.iter()) {
+		diff |= x ^ y;
+	}
+	diff == 0
+}
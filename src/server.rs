@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+type Closer = Box<dyn Fn() + Send>;
+
+struct Connections {
+	next_id: u64,
+	closers: HashMap<u64, Closer>,
+}
+
+// Tracks open client connections so that shutdown can stop reading from them
+// and wait for the commands already received to be answered.
+pub struct Server {
+	stopping: AtomicBool,
+	connections: Mutex<Connections>,
+	idle: Condvar,
+	wakers: Mutex<Vec<Closer>>,
+}
+
+pub struct Guard {
+	server: Arc<Server>,
+	id: u64,
+}
+
+impl Drop for Guard {
+	fn drop(&mut self) {
+		if let Ok(mut connections) = self.server.connections.lock() {
+			connections.closers.remove(&self.id);
+			if connections.closers.is_empty() {
+				self.server.idle.notify_all();
+			}
+		}
+	}
+}
+
+impl Server {
+	pub fn new() -> Arc<Server> {
+		Arc::new(Server {
+			stopping: AtomicBool::new(false),
+			connections: Mutex::new(Connections {
+				next_id: 0,
+				closers: HashMap::new(),
+			}),
+			idle: Condvar::new(),
+			wakers: Mutex::new(Vec::new()),
+		})
+	}
+	pub fn is_stopping(&self) -> bool {
+		self.stopping.load(Ordering::SeqCst)
+	}
+	// Registers a connection; `closer` must make the connection's pending read
+	// return end-of-file. Returns None once shutdown has begun.
+	pub fn register(server: &Arc<Server>, closer: Closer) -> Option<Guard> {
+		let mut connections = server.connections.lock().ok()?;
+		if server.is_stopping() {
+			return None;
+		}
+		let id = connections.next_id;
+		connections.next_id += 1;
+		connections.closers.insert(id, closer);
+		Some(Guard {
+			server: server.clone(),
+			id,
+		})
+	}
+	// Registers a function that unblocks a listener's accept loop, usually by
+	// connecting to it, so the loop can notice the shutdown.
+	pub fn add_waker(&self, waker: Closer) {
+		if let Ok(mut wakers) = self.wakers.lock() {
+			wakers.push(waker);
+		}
+	}
+	pub fn stop(&self) {
+		if let Ok(connections) = self.connections.lock() {
+			self.stopping.store(true, Ordering::SeqCst);
+			for closer in connections.closers.values() {
+				closer();
+			}
+		}
+		if let Ok(wakers) = self.wakers.lock() {
+			for waker in wakers.iter() {
+				waker();
+			}
+		}
+	}
+	pub fn wait(&self) {
+		if let Ok(mut connections) = self.connections.lock() {
+			while !connections.closers.is_empty() {
+				connections = match self.idle.wait(connections) {
+					Ok(connections) => connections,
+					Err(_) => return,
+				};
+			}
+		}
+	}
+}
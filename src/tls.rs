@@ -0,0 +1,77 @@
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::TcpStream;
+use std::sync::Arc;
+
+use rustls::{RootCertStore, ServerConfig, ServerConnection};
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls_pemfile;
+use webpki::EndEntityCert;
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+	let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+	let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+		.collect::<Result<Vec<_>, _>>()
+		.map_err(|e| format!("{}: {}", path, e))?;
+	if certs.is_empty() {
+		return Err(format!("{}: no certificates found", path));
+	}
+	Ok(certs)
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>, String> {
+	let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+	rustls_pemfile::private_key(&mut BufReader::new(file))
+		.map_err(|e| format!("{}: {}", path, e))?
+		.ok_or(format!("{}: no private key found", path))
+}
+
+// Builds the TLS server configuration. When `client_ca` is set, clients must
+// present a certificate signed by one of the CAs it contains.
+pub fn server_config(cert: &str, key: &str, client_ca: &str) -> Result<Arc<ServerConfig>, String> {
+	let provider = Arc::new(ring::default_provider());
+	let builder = ServerConfig::builder_with_provider(provider.clone())
+		.with_safe_default_protocol_versions()
+		.map_err(|e| e.to_string())?;
+	let builder = if client_ca.is_empty() {
+			builder.with_no_client_auth()
+		} else {
+			let mut roots = RootCertStore::empty();
+			for ca in load_certs(client_ca)? {
+				roots.add(ca).map_err(|e| format!("{}: {}", client_ca, e))?;
+			}
+			let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+				.build()
+				.map_err(|e| format!("{}: {}", client_ca, e))?;
+			builder.with_client_cert_verifier(verifier)
+		};
+	let config = builder
+		.with_single_cert(load_certs(cert)?, load_key(key)?)
+		.map_err(|e| format!("{}: {}", cert, e))?;
+	Ok(Arc::new(config))
+}
+
+// Completes the handshake, so that the client's certificate is known before
+// the first request is read.
+pub fn handshake(conn: &mut ServerConnection, stream: &mut TcpStream) -> io::Result<()> {
+	while conn.is_handshaking() {
+		conn.complete_io(stream)?;
+	}
+	Ok(())
+}
+
+// The DNS names of the client's certificate, which the verifier has already
+// checked against tls_client_ca. Empty without client authentication.
+pub fn client_names(conn: &ServerConnection) -> Vec<String> {
+	let cert = match conn.peer_certificates().and_then(|certs| certs.first()) {
+		Some(cert) => cert,
+		None => return Vec::new(),
+	};
+	match EndEntityCert::try_from(cert) {
+		Ok(cert) => cert.valid_dns_names().map(String::from).collect(),
+		Err(_) => Vec::new(),
+	}
+}
@@ -0,0 +1,386 @@
+extern crate serde_json;
+extern crate sessiond_client;
+
+use std::env;
+use std::fs;
+use std::io::prelude::*;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Command};
+use std::thread;
+use std::time::Duration;
+
+use serde_json::Value;
+use sessiond_client::{Client, Error};
+
+struct Daemon {
+	child: Child,
+	dir: PathBuf,
+}
+
+impl Daemon {
+	fn spawn(name: &str) -> Daemon {
+		Daemon::spawn_with(name, &[])
+	}
+	fn spawn_with(name: &str, args: &[&str]) -> Daemon {
+		Daemon::spawn_in(data_dir(name), args)
+	}
+	// Starts the daemon on a data directory the test has already filled.
+	fn spawn_in(dir: PathBuf, args: &[&str]) -> Daemon {
+		let child = Command::new(env!("CARGO_BIN_EXE_sessiond"))
+			.arg("--dir").arg(&dir)
+			.arg("--socket").arg(dir.join("sessiond.sock"))
+			.args(args)
+			.spawn()
+			.unwrap();
+		let daemon = Daemon { child, dir };
+		for _ in 0..100 {
+			if Client::connect(daemon.socket()).is_ok() {
+				return daemon;
+			}
+			thread::sleep(Duration::from_millis(50));
+		}
+		panic!("sessiond did not start");
+	}
+	fn socket(&self) -> PathBuf {
+		self.dir.join("sessiond.sock")
+	}
+	// Stops the daemon as a crash would, leaving its data directory behind.
+	fn kill(&mut self) {
+		let _ = self.child.kill();
+		let _ = self.child.wait();
+	}
+}
+
+fn data_dir(name: &str) -> PathBuf {
+	let mut dir = env::temp_dir();
+	dir.push(format!("sessiond-client-{}-{}", process::id(), name));
+	let _ = fs::remove_dir_all(&dir);
+	fs::create_dir_all(&dir).unwrap();
+	dir
+}
+
+fn sessionctl(dir: &Path, args: &[&str]) -> String {
+	let output = Command::new(env!("CARGO_BIN_EXE_sessionctl"))
+		.arg("--dir").arg(dir)
+		.args(args)
+		.output()
+		.unwrap();
+	assert!(output.status.success(), "sessionctl {:?}: {}", args, String::from_utf8_lossy(&output.stderr));
+	String::from_utf8(output.stdout).unwrap()
+}
+
+// Writes users.cdb from user records in the daemon's own format.
+fn import_users(dir: &Path, users: &[(&str, &str)]) {
+	let mut dump = String::new();
+	for &(name, record) in users {
+		dump.push_str(format!("+{},{}:{}->{}\n", name.len(), record.len(), name, record).as_str());
+	}
+	dump.push('\n');
+	let path = dir.join("users.dump");
+	fs::write(&path, dump).unwrap();
+	sessionctl(dir, &["import", path.to_str().unwrap()]);
+}
+
+// The user's record as users.cdb and the journal have it.
+fn stored_user(dir: &Path, name: &str) -> Value {
+	let dump: Value = serde_json::from_str(sessionctl(dir, &["--json", "dump"]).as_str()).unwrap();
+	dump["users"].as_array().unwrap().iter().find(|user| user["name"] == name).cloned().unwrap_or(Value::Null)
+}
+
+impl Drop for Daemon {
+	fn drop(&mut self) {
+		self.kill();
+		let _ = fs::remove_dir_all(&self.dir);
+	}
+}
+
+#[test]
+fn login_session_logout() {
+	let daemon = Daemon::spawn("login");
+	let mut client = Client::connect(daemon.socket()).unwrap();
+
+	client.create_user("alice", "secret").unwrap();
+	assert!(matches!(client.create_user("alice", "other"), Err(Error::UserExists)));
+	client.auth("alice", "secret").unwrap();
+	assert!(matches!(client.auth("alice", "wrong"), Err(Error::AuthenticationFailed)));
+
+	let session_id = client.login("alice", "secret").unwrap();
+	assert_eq!(&*client.session(&session_id).unwrap(), "alice");
+	assert_eq!(&*client.logout(&session_id).unwrap(), "alice");
+	assert!(matches!(client.session(&session_id), Err(Error::SessionNotFound)));
+	assert!(matches!(client.login("alice", "wrong"), Err(Error::LoginFailed)));
+
+	client.update_user("alice", "changed").unwrap();
+	client.login("alice", "changed").unwrap();
+	client.unlock_user("alice").unwrap();
+	client.save().unwrap();
+	client.delete_user("alice").unwrap();
+	assert!(matches!(client.delete_user("alice"), Err(Error::UserNotFound)));
+}
+
+#[test]
+fn plaintext_passwords_are_hashed_on_first_use() {
+	let dir = data_dir("upgrade");
+	import_users(&dir, &[("bob", "secret 1 0 0 0 0 0 0 0")]);
+	let daemon = Daemon::spawn_in(dir, &[]);
+	let mut client = Client::connect(daemon.socket()).unwrap();
+	assert!(matches!(client.auth("bob", "wrong"), Err(Error::AuthenticationFailed)));
+	assert_eq!(stored_user(&daemon.dir, "bob")["password"], "secret");
+	client.auth("bob", "secret").unwrap();
+	let stored = stored_user(&daemon.dir, "bob");
+	assert!(stored["password"].as_str().unwrap().starts_with("$argon2id$"));
+	assert_eq!(stored["fail_count"], 0);
+	client.login("bob", "secret").unwrap();
+	assert!(matches!(client.login("bob", "hashed"), Err(Error::LoginFailed)));
+}
+
+#[test]
+fn changes_survive_a_crash() {
+	let mut daemon = Daemon::spawn("crash");
+	let mut client = Client::connect(daemon.socket()).unwrap();
+	client.create_user("carol", "one").unwrap();
+	client.create_user("dan", "secret").unwrap();
+	client.save().unwrap();
+	client.update_user("carol", "two").unwrap();
+	client.delete_user("dan").unwrap();
+	client.create_user("erin", "secret").unwrap();
+	daemon.kill();
+
+	let daemon = Daemon::spawn_in(daemon.dir.clone(), &[]);
+	let mut client = Client::connect(daemon.socket()).unwrap();
+	client.auth("carol", "two").unwrap();
+	assert!(matches!(client.auth("dan", "secret"), Err(Error::AuthenticationFailed)));
+	client.auth("erin", "secret").unwrap();
+}
+
+#[test]
+fn sessionctl_reads_the_daemon_config() {
+	let dir = data_dir("ctlconfig");
+	let path_config = dir.join("sessiond.toml");
+	fs::write(&path_config, "users_cdb = \"accounts.cdb\"\nusers_journal = \"accounts.journal\"\n").unwrap();
+	let config = path_config.to_str().unwrap();
+	// Written before lockouts were counted, and locked after five failures.
+	let record = "secret 1 0 0 0 100 5 100";
+	let path_dump = dir.join("users.dump");
+	fs::write(&path_dump, format!("+4,{}:dave->{}\n\n", record.len(), record)).unwrap();
+	sessionctl(&dir, &["--config", config, "import", path_dump.to_str().unwrap()]);
+	assert!(dir.join("accounts.cdb").exists());
+	assert!(! dir.join("users.cdb").exists());
+	assert!(sessionctl(&dir, &["--config", config, "verify"]).starts_with("1 users"));
+	sessionctl(&dir, &["--config", config, "unlock", "dave"]);
+	assert!(dir.join("accounts.journal").exists());
+
+	let daemon = Daemon::spawn_in(dir, &["--config", config]);
+	let mut client = Client::connect(daemon.socket()).unwrap();
+	client.login("dave", "secret").unwrap();
+}
+
+#[test]
+fn sessions_are_listed_and_revoked() {
+	let daemon = Daemon::spawn_with("revoke", &["--revoke-on-password-change", "true"]);
+	let mut client = Client::connect(daemon.socket()).unwrap();
+	let first = client.create_user("gina", "secret").unwrap();
+	let second = client.login("gina", "secret").unwrap();
+	let mut sessions = client.list_sessions("gina").unwrap();
+	sessions.sort();
+	let mut expected = vec![first.clone(), second.clone()];
+	expected.sort();
+	assert_eq!(sessions, expected);
+	client.logout(&first).unwrap();
+	assert_eq!(client.list_sessions("gina").unwrap(), vec![second.clone()]);
+	client.login("gina", "secret").unwrap();
+	assert_eq!(client.revoke_sessions("gina").unwrap(), 2);
+	assert!(matches!(client.session(&second), Err(Error::SessionNotFound)));
+	assert!(client.list_sessions("gina").unwrap().is_empty());
+
+	let third = client.login("gina", "secret").unwrap();
+	client.update_user("gina", "changed").unwrap();
+	assert!(matches!(client.session(&third), Err(Error::SessionNotFound)));
+}
+
+#[test]
+fn session_info_reports_origin() {
+	let daemon = Daemon::spawn("info");
+	let mut client = Client::connect(daemon.socket()).unwrap();
+	let created = client.create_user("ivy", "secret").unwrap();
+	client.set_label(Some("Mozilla/5.0 (X11; \"Linux\")"));
+	let session_id = client.login("ivy", "secret").unwrap();
+
+	let info = client.session_info(&session_id).unwrap();
+	assert_eq!(&*info.name, "ivy");
+	assert_eq!(info.method, "password");
+	assert_eq!(info.label, "Mozilla/5.0 (X11; \"Linux\")");
+	assert!(info.source.starts_with("unix:uid="));
+	assert_eq!(info.last_source, info.source);
+	assert!(info.created <= info.last_accessed);
+
+	let info = client.session_info(&created).unwrap();
+	assert_eq!(info.method, "create");
+	assert_eq!(info.label, "");
+	client.logout(&session_id).unwrap();
+	assert!(matches!(client.session_info(&session_id), Err(Error::SessionNotFound)));
+}
+
+#[test]
+fn session_attributes() {
+	let daemon = Daemon::spawn_with("attributes", &["--attribute-value-max", "16", "--attribute-total-max", "40"]);
+	let mut client = Client::connect(daemon.socket()).unwrap();
+	let session_id = client.create_user("jack", "secret").unwrap();
+	client.set_attribute(&session_id, "theme", "dark").unwrap();
+	client.set_attribute(&session_id, "cart item", "two \"words\"\n").unwrap();
+	client.set_attribute(&session_id, "empty", "").unwrap();
+	assert_eq!(client.get_attribute(&session_id, "cart item").unwrap(), "two \"words\"\n");
+	assert_eq!(client.get_attribute(&session_id, "empty").unwrap(), "");
+	assert_eq!(client.attribute_keys(&session_id).unwrap(), vec!["cart item", "empty", "theme"]);
+
+	assert!(matches!(client.set_attribute(&session_id, "big", "x".repeat(17).as_str()), Err(Error::AttributeLimit)));
+	assert!(matches!(client.set_attribute(&session_id, "more", "x".repeat(9).as_str()), Err(Error::AttributeLimit)));
+	assert!(matches!(client.set_attribute(&session_id, "", "x"), Err(Error::AttributeLimit)));
+	client.set_attribute(&session_id, "theme", "light").unwrap();
+
+	client.delete_attribute(&session_id, "theme").unwrap();
+	assert!(matches!(client.get_attribute(&session_id, "theme"), Err(Error::AttributeNotFound)));
+	assert!(matches!(client.delete_attribute(&session_id, "theme"), Err(Error::AttributeNotFound)));
+
+	client.logout(&session_id).unwrap();
+	assert!(matches!(client.get_attribute(&session_id, "empty"), Err(Error::SessionNotFound)));
+	assert!(matches!(client.delete_attribute(&session_id, "empty"), Err(Error::SessionNotFound)));
+	assert!(matches!(client.attribute_keys(&session_id), Err(Error::SessionNotFound)));
+}
+
+#[test]
+fn session_limit_rejects_or_evicts() {
+	let daemon = Daemon::spawn_with("limit-reject", &["--session-limit", "2"]);
+	let mut client = Client::connect(daemon.socket()).unwrap();
+	client.create_user("hank", "secret").unwrap();
+	client.login("hank", "secret").unwrap();
+	assert!(matches!(client.auth("hank", "wrong"), Err(Error::AuthenticationFailed)));
+	assert!(matches!(client.login("hank", "secret"), Err(Error::SessionLimit)));
+	// A login turned away is not recorded as a successful one.
+	assert_eq!(stored_user(&daemon.dir, "hank")["fail_count"], 1);
+	assert!(matches!(client.create_user("hank", "secret"), Err(Error::UserExists)));
+
+	let daemon = Daemon::spawn_with("limit-evict", &["--session-limit", "2", "--session-limit-policy", "evict-oldest"]);
+	let mut client = Client::connect(daemon.socket()).unwrap();
+	let first = client.create_user("hank", "secret").unwrap();
+	let second = client.login("hank", "secret").unwrap();
+	let third = client.login("hank", "secret").unwrap();
+	assert_eq!(client.list_sessions("hank").unwrap(), vec![second.clone(), third.clone()]);
+	assert!(matches!(client.session(&first), Err(Error::SessionNotFound)));
+	// A CREATE that fails leaves the existing sessions alone.
+	assert!(matches!(client.create_user("hank", "secret"), Err(Error::UserExists)));
+	assert_eq!(client.list_sessions("hank").unwrap(), vec![second, third]);
+}
+
+#[test]
+fn usernames_are_normalized() {
+	let daemon = Daemon::spawn_with("names", &["--username-case-fold", "true"]);
+	let mut client = Client::connect(daemon.socket()).unwrap();
+	client.create_user("Fran\u{e7}ois", "secret").unwrap();
+	assert!(matches!(client.create_user("franc\u{327}ois", "secret"), Err(Error::UserExists)));
+	let session_id = client.login("FRANC\u{327}OIS", "secret").unwrap();
+	assert_eq!(&*client.session(&session_id).unwrap(), "fran\u{e7}ois");
+	assert!(matches!(client.create_user("a b", "secret"), Err(Error::InvalidName)));
+	assert!(matches!(client.create_user("semi;colon", "secret"), Err(Error::InvalidName)));
+}
+
+#[test]
+fn names_stored_before_normalization_stay_reachable() {
+	let dir = data_dir("legacynames");
+	import_users(&dir, &[
+		("Bob", "secret 1 0 0 0 0 0 0 0"),
+		("dave", "secret 1 0 0 0 100 5 100 1"),
+	]);
+	sessionctl(&dir, &["unlock", "DAVE"]);
+	let daemon = Daemon::spawn_in(dir, &[]);
+	let mut client = Client::connect(daemon.socket()).unwrap();
+	client.auth("Bob", "secret").unwrap();
+	client.auth("bob", "secret").unwrap();
+	let session_id = client.login("BOB", "secret").unwrap();
+	assert_eq!(&*client.session(&session_id).unwrap(), "Bob");
+	assert!(matches!(client.create_user("bob", "other"), Err(Error::UserExists)));
+	assert!(matches!(client.create_user("BOB", "other"), Err(Error::UserExists)));
+	client.login("Dave", "secret").unwrap();
+}
+
+#[test]
+fn arguments_are_quoted() {
+	let daemon = Daemon::spawn("quoting");
+	let mut client = Client::connect(daemon.socket()).unwrap();
+	let password = "correct horse \"battery\" \\ staple\t\r\n";
+	client.create_user("erin", password).unwrap();
+	client.auth("erin", password).unwrap();
+	assert!(matches!(client.auth("erin", "correct"), Err(Error::AuthenticationFailed)));
+	client.update_user("erin", "").unwrap();
+	client.auth("erin", "").unwrap();
+}
+
+#[test]
+fn long_requests_are_rejected() {
+	let daemon = Daemon::spawn_with("long", &["--request-max", "5000"]);
+	let mut client = Client::connect(daemon.socket()).unwrap();
+	client.create_user("erin", "secret").unwrap();
+	let password = "x".repeat(6000);
+	assert!(matches!(client.auth("erin", password.as_str()), Err(Error::Server(ref msg)) if msg == "Request is too long."));
+	client.auth("erin", "secret").unwrap();
+}
+
+#[test]
+fn locked_accounts_are_reported() {
+	let daemon = Daemon::spawn("locked");
+	let mut client = Client::connect(daemon.socket()).unwrap();
+	client.create_user("dave", "secret").unwrap();
+	for _ in 0..5 {
+		assert!(matches!(client.auth("dave", "wrong"), Err(Error::AuthenticationFailed)));
+	}
+	assert!(matches!(client.login("dave", "secret"), Err(Error::Locked)));
+	client.unlock_user("dave").unwrap();
+	client.login("dave", "secret").unwrap();
+}
+
+#[test]
+fn blank_lines_are_answered() {
+	let daemon = Daemon::spawn("blank");
+	let mut stream = UnixStream::connect(daemon.socket()).unwrap();
+	stream.write_all(b"\r\nHELLO 2\r\n  \r\nQUIT\r\n").unwrap();
+	let mut replies = String::new();
+	stream.read_to_string(&mut replies).unwrap();
+	assert_eq!(replies, "ERROR\r\nOK 2\r\nNG SYNTAX Empty request.\r\nOK\r\n");
+}
+
+#[test]
+fn version_1_lines_split_on_blanks() {
+	let daemon = Daemon::spawn("v1words");
+	let mut client = Client::connect(daemon.socket()).unwrap();
+	client.create_user("bob", "\"secret").unwrap();
+	let mut stream = UnixStream::connect(daemon.socket()).unwrap();
+	stream.write_all(b"AUTH bob \"secret\r\nAUTH bob \"secret extra\r\nHELLO 2\r\nAUTH bob \"secret\r\nQUIT\r\n").unwrap();
+	let mut replies = String::new();
+	stream.read_to_string(&mut replies).unwrap();
+	assert_eq!(replies, "OK\r\nOK\r\nOK 2\r\nNG SYNTAX Unterminated quoted string.\r\nOK\r\n");
+}
+
+#[test]
+fn reconnects_after_the_connection_is_lost() {
+	let daemon = Daemon::spawn("reconnect");
+	let mut client = Client::connect(daemon.socket()).unwrap();
+	client.create_user("bob", "secret").unwrap();
+	drop(daemon);
+	assert!(matches!(client.auth("bob", "secret"), Err(Error::Io(_))));
+
+	let daemon = Daemon::spawn("reconnect");
+	let mut client2 = Client::connect(daemon.socket()).unwrap();
+	client2.create_user("bob", "secret").unwrap();
+	client.auth("bob", "secret").unwrap();
+}
+
+#[test]
+fn requests_time_out() {
+	let mut path = env::temp_dir();
+	path.push(format!("sessiond-client-{}-silent.sock", process::id()));
+	let _ = fs::remove_file(&path);
+	let _listener = UnixListener::bind(&path).unwrap();
+	assert!(matches!(Client::connect_timeout(&path, Some(Duration::from_millis(100))), Err(Error::Io(_))));
+	let _ = fs::remove_file(&path);
+}